@@ -0,0 +1,197 @@
+use ::std::cell::RefCell;
+use ::std::collections::HashMap;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+use ::std::time::SystemTime;
+use ::yaml_rust::Yaml;
+use ::yaml_rust::yaml::Hash;
+use ::parse::*;
+use ::errors::*;
+use ::{Dialect, Ignore};
+
+// Runs the jobs in a manifest like a makefile would: a job's outputs are only written again when one of them is older than the
+// template, a partial it includes, a values file, or the manifest itself.  Values files are parsed once however many jobs use them.
+// A job with `each` renders once per item of that value, with the item as the context, and its output path is itself a template
+// rendered the same way.
+
+#[derive(Debug, PartialEq)]
+struct Job {
+	template: PathBuf,
+	values: Vec<(PathBuf, Option<usize>)>, // Merged in order over the template's own values
+	output: String,
+	config: Option<Yaml>, // Merged over _config last
+	each: Option<YamlPath>,
+}
+
+fn string(job: &Yaml, key: &str) -> Result<Option<String>> {
+	match job[key] {
+		Yaml::BadValue => Ok(None),
+		Yaml::String(ref s) => Ok(Some(s.to_string())),
+		_ => bail!(format!("{} must be a string", key)),
+	}
+}
+
+fn jobs(manifest: &Yaml, dir: &Path) -> Result<Vec<Job>> { // Paths in the manifest are relative to its directory
+	let list = match manifest["jobs"] {
+		Yaml::Array(ref a) => a,
+		_ => bail!("The manifest must have a jobs sequence"),
+	};
+	let job = |job: &Yaml| -> Result<Job> {
+		let values = match job["values"] {
+			Yaml::BadValue => vec![],
+			Yaml::String(ref s) => vec![s.to_string()],
+			Yaml::Array(ref a) => a.iter().map(|v| v.as_str().map(|s| s.to_string()).ok_or_else(|| Error::from("values must be strings"))).collect::<Result<_>>()?,
+			_ => bail!("values must be a string or a sequence of strings"),
+		};
+		let config = match job["config"] {
+			Yaml::BadValue => None,
+			Yaml::Hash(_) => Some(job["config"].clone()),
+			_ => bail!("config must be a mapping"),
+		};
+		Ok(Job {
+			template: dir.join(string(job, "template")?.ok_or_else(|| Error::from("template is required"))?),
+			values: values.iter().map(|spec| { let (fname, document) = ::split_document(spec); (dir.join(fname), document) }).collect(),
+			output: string(job, "output")?.ok_or_else(|| Error::from("output is required"))?,
+			config,
+			each: string(job, "each")?.map(|each| each.split('.').map(|k| YamlPathElem::Down(k.to_string())).collect()),
+		})
+	};
+	list.iter().enumerate().map(|(i, j)| job(j).chain_err(|| format!("Job {} is invalid", i + 1))).collect()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+	fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Default)]
+struct Builder {
+	values: HashMap<PathBuf, Vec<Yaml>>, // Every document of each values file read so far
+}
+
+impl Builder {
+	fn load(&mut self, path: &Path, document: Option<usize>) -> Result<Vec<Yaml>> {
+		if ! self.values.contains_key(path) {
+			let docs = ::read_file(&path.to_string_lossy()).and_then(|text| ::yaml::load(&text, None)).chain_err(|| format!("Failed to load values file {}", path.display()))?;
+			self.values.insert(path.to_path_buf(), docs);
+		}
+		let docs = &self.values[path];
+		match document {
+			Some(n) => docs.get(n).map(|doc| vec![doc.clone()]).ok_or_else(|| Error::from(format!("Document {} of {} was requested, but there are only {}", n, path.display(), docs.len()))),
+			None => Ok(docs.to_vec()),
+		}
+	}
+	fn run(&mut self, job: &Job, manifest: &Path, dir: &Path, force: bool) -> Result<Vec<PathBuf>> { // The outputs written
+		let template = job.template.to_string_lossy().to_string();
+		let input = ::read_file(&template)?;
+		let mut parser = Parser::new(&input);
+		let mut layers = parser.get_yaml()?.unwrap_or_default();
+		for (path, document) in &job.values { layers.extend(self.load(path, *document)?); }
+		if let Some(ref config) = job.config {
+			let mut h = Hash::new();
+			h.insert(Yaml::String("_config".to_string()), config.clone());
+			layers.push(Yaml::Hash(h));
+		}
+		let mut values = ::yaml::merge(layers).chain_err(|| "Failed to merge values")?;
+		let pargs = ::ParseArgs::from_yaml(&mut values).chain_err(|| "Error parsing template arguments")?;
+		::schema::validate(&mut values)?;
+		if pargs.sort_keys { ::yaml::sort_keys(&mut values); }
+		let contexts = match job.each {
+			Some(_) if pargs.dialect != Dialect::Tpl => bail!("each only works with tpl templates"),
+			Some(ref each) => match ::yaml::get(&values, each) {
+				Yaml::Array(ref a) if ! a.is_empty() => ::section_items(&values, each, &None, &Modifiers::default(), &::Scope::default(), Pos::default(), Ignore::Fail)?,
+				Yaml::Hash(ref h) if ! h.is_empty() => ::section_items(&values, each, &None, &Modifiers::default(), &::Scope::default(), Pos::default(), Ignore::Fail)?,
+				_ => bail!(format!("each must be a sequence or mapping with at least one item, but {} isn't", ::print::path(each))),
+			},
+			None => vec![vec![]],
+		};
+		let output_tree = Parser::new(&job.output).get_tpl(&pargs.open, &pargs.close).chain_err(|| "Failed to parse output")?;
+		let outputs = contexts.iter().map(|context| ::render(&values, &output_tree, context, &::Scope::default(), Ignore::Fail).map(|out| dir.join(out)))
+			.collect::<Result<Vec<PathBuf>>>().chain_err(|| "Failed to render output")?;
+		let partials = RefCell::new(vec![]); // Only known once a Mustache template is rendered
+		let rendered = match pargs.dialect { // All of them before writing any, so an error doesn't leave the outputs half updated
+			Dialect::Tpl => {
+				let tree = parser.get_tpl(&pargs.open, &pargs.close)?;
+				contexts.iter().map(|context| ::render(&values, &tree, context, &::Scope::default(), pargs.ignore)).collect::<Result<Vec<String>>>()?
+			},
+			Dialect::Mustache => vec![::render_mustache(&template, &parser.get_text()?, &values, &pargs, &partials)?],
+		};
+		let partials = partials.into_inner().into_iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+		let inputs = [manifest, job.template.as_path()].iter().cloned().chain(job.values.iter().map(|(path, _)| path.as_path()))
+			.chain(partials.iter().map(|path| path.as_path())).collect::<Vec<&Path>>();
+		let newest = inputs.iter().filter_map(|path| modified(path)).max();
+		if ! force && outputs.iter().all(|out| match (modified(out), newest) { (Some(out), Some(newest)) => out >= newest, _ => false }) { return Ok(vec![]); }
+		for (out, text) in outputs.iter().zip(rendered) {
+			if let Some(parent) = out.parent() { fs::create_dir_all(parent).chain_err(|| format!("Failed to create {}", parent.display()))?; }
+			fs::write(out, text).chain_err(|| format!("Failed to write to {}", out.display()))?;
+		}
+		Ok(outputs)
+	}
+}
+
+pub fn build(manifest: &str, force: bool) -> Result<()> {
+	let path = Path::new(manifest);
+	let dir = path.parent().unwrap_or_else(|| Path::new(""));
+	let text = ::read_file(manifest)?;
+	let jobs = ::yaml::load(&text, Some(0)).and_then(|mut docs| jobs(&docs.pop().unwrap_or(Yaml::BadValue), dir)).chain_err(|| format!("Invalid manifest {}", manifest))?;
+	let mut builder = Builder::default();
+	for job in &jobs {
+		for out in builder.run(job, path, dir, force).chain_err(|| format!("Failed to render {}", job.template.display()))? {
+			eprintln!("Wrote {}", out.display());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn manifest_jobs() {
+		let manifest = ::yaml::load("jobs:\n  - template: a.tpl\n    values: [b.yaml, c.yaml#1]\n    output: out/a\n    config: {open: '[['}\n  - template: d.tpl\n    values: e.yaml\n    each: f.g\n    output: '{{name}}.conf'\n", None).unwrap().pop().unwrap();
+		let jobs = jobs(&manifest, Path::new("dir")).unwrap();
+		assert_eq!(jobs[0], Job {
+			template: PathBuf::from("dir/a.tpl"),
+			values: vec![(PathBuf::from("dir/b.yaml"), None), (PathBuf::from("dir/c.yaml"), Some(1))],
+			output: "out/a".to_string(),
+			config: Some(::yaml::load("open: '[['", None).unwrap().pop().unwrap()),
+			each: None,
+		});
+		assert_eq!(jobs[1].values, vec![(PathBuf::from("dir/e.yaml"), None)]);
+		assert_eq!(jobs[1].each, Some(vec![YamlPathElem::Down("f".to_string()), YamlPathElem::Down("g".to_string())]));
+		assert!(super::jobs(&::yaml::load("jobs:\n  - output: x\n", None).unwrap().pop().unwrap(), Path::new("")).is_err());
+		assert!(super::jobs(&::yaml::load("jobs:\n  - {template: x, output: y, config: 1}\n", None).unwrap().pop().unwrap(), Path::new("")).is_err());
+	}
+	#[test]
+	fn stale_outputs() {
+		let dir = ::std::env::temp_dir().join(format!("tpl-build-{}", ::std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let manifest = dir.join("tpl.yaml");
+		fs::write(&manifest, "jobs:\n  - {template: a.tpl, values: v.yaml, each: xs, output: 'out/{{?}}'}\n  - {template: a.tpl, values: v.yaml, each: none, output: b}\n").unwrap();
+		fs::write(dir.join("a.tpl"), "{{}}").unwrap();
+		fs::write(dir.join("v.yaml"), "xs: [p, q]\n").unwrap();
+		let set_modified = |path: &Path, secs: u64| fs::File::options().write(true).open(path).unwrap().set_modified(SystemTime::UNIX_EPOCH + ::std::time::Duration::from_secs(secs)).unwrap();
+		for input in &["tpl.yaml", "a.tpl", "v.yaml"] { set_modified(&dir.join(input), 1000); }
+		let jobs = jobs(&::yaml::load(&::read_file(&manifest.to_string_lossy()).unwrap(), Some(0)).unwrap().pop().unwrap(), &dir).unwrap();
+		let mut builder = Builder::default();
+		let written = vec![dir.join("out/0"), dir.join("out/1")];
+		assert_eq!(builder.run(&jobs[0], &manifest, &dir, false).unwrap(), written); // Missing outputs
+		assert_eq!(::read_file(&written[1].to_string_lossy()).unwrap(), "q");
+		assert_eq!(builder.run(&jobs[0], &manifest, &dir, false).unwrap(), Vec::<PathBuf>::new()); // Newer than every input
+		assert_eq!(builder.run(&jobs[0], &manifest, &dir, true).unwrap(), written);
+		set_modified(&written[0], 500);
+		assert_eq!(builder.run(&jobs[0], &manifest, &dir, false).unwrap(), written); // Any output being older renders them all
+		set_modified(&dir.join("a.tpl"), 4000000000);
+		assert_eq!(builder.run(&jobs[0], &manifest, &dir, false).unwrap(), written);
+		assert!(builder.run(&jobs[1], &manifest, &dir, false).is_err()); // Nothing to fan out over
+		fs::write(dir.join("m.tpl"), "---\n_config: {dialect: mustache}\n...\n{{> p}}").unwrap();
+		fs::write(dir.join("p.mustache"), "{{#xs}}{{.}}{{/xs}}").unwrap();
+		for input in &["m.tpl", "p.mustache"] { set_modified(&dir.join(input), 1000); }
+		let job = Job { template: dir.join("m.tpl"), values: vec![(dir.join("v.yaml"), None)], output: "m".to_string(), config: None, each: None };
+		assert_eq!(builder.run(&job, &manifest, &dir, false).unwrap(), vec![dir.join("m")]);
+		assert_eq!(::read_file(&dir.join("m").to_string_lossy()).unwrap(), "pq");
+		assert_eq!(builder.run(&job, &manifest, &dir, false).unwrap(), Vec::<PathBuf>::new());
+		set_modified(&dir.join("p.mustache"), 4000000000);
+		assert_eq!(builder.run(&job, &manifest, &dir, false).unwrap(), vec![dir.join("m")]); // Touching a partial
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
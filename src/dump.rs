@@ -0,0 +1,74 @@
+use ::yaml_rust::{Yaml, YamlEmitter};
+use ::parse::*;
+use ::errors::*;
+
+// Shows what the parser and value merging produced, for debugging templates.  Expressions are written as the parser's own
+// structures rather than template syntax, so that a tag which parsed differently than intended is easy to spot.
+
+fn span(source: &Source) -> String {
+	if source.end.is_empty() { format!(" at {} {:?}", source.pos, source.text) }
+	else { format!(" at {} {:?} to {:?}", source.pos, source.text, source.end) }
+}
+
+fn node(node: &Node) -> String {
+	match node {
+		Node::Literal(ref s, raw) => format!("{} {:?}", if *raw { "RawLiteral" } else { "Literal" }, s),
+		Node::DirectSub(ref expr, ref format, ref source) =>
+			format!("DirectSub {:?}{}{}", expr, format.as_ref().map(|f| format!(" format {:?}", f.to_string())).unwrap_or_default(), span(source)),
+		Node::CondSub(ref expr, direct, ref name, ref mods, _, ref source) => {
+			let mut ret = format!("{} {:?}", if *direct { "Section" } else { "InvertedSection" }, expr);
+			if let Some(ref name) = name { ret.push_str(&format!(" as {}", name)); }
+			if let Some(ref filter) = mods.filter { ret.push_str(&format!(" where {:?}", filter)); }
+			if let Some((ref key, desc)) = mods.sort { ret.push_str(&format!(" sort_by {:?}{}", key, if desc { " desc" } else { "" })); }
+			if let Some(limit) = mods.limit { ret.push_str(&format!(" limit {}", limit)); }
+			ret + &span(source)
+		},
+		Node::With(ref path, ref name, _, ref source) => format!("With {:?} as {}{}", path, name, span(source)),
+		Node::Let(ref name, ref path, ref source) => format!("Let {} = {:?}{}", name, path, span(source)),
+		Node::Define(ref name, _) => format!("Define {}", name),
+		Node::Call(ref name, ref context, ref params, ref source) => format!("Call {} {:?} {:?}{}", name, context, params, span(source)),
+		Node::KeySub(n) => format!("KeySub {}", n),
+		Node::Comment(ref s, _) => format!("Comment {:?}", s),
+		Node::SetDelim(ref open, ref close) => format!("SetDelim {:?} {:?}", open, close),
+	}
+}
+
+fn nodes(tree: &[Node], depth: usize, ret: &mut String) {
+	for n in tree {
+		ret.push_str(&format!("{}{}\n", "  ".repeat(depth), node(n)));
+		match n {
+			Node::CondSub(.., ref children, _) | Node::With(_, _, ref children, _) | Node::Define(_, ref children) => nodes(children, depth + 1, ret),
+			_ => (),
+		}
+	}
+}
+
+pub fn ast(tree: &[Node]) -> String { // One node per line, with children indented under their section
+	let mut ret = String::new();
+	nodes(tree, 0, &mut ret);
+	ret
+}
+
+pub fn values(values: &Yaml) -> Result<String> {
+	let mut ret = String::new();
+	YamlEmitter::new(&mut ret).dump(values).chain_err(|| "Failed to write YAML")?;
+	ret.push('\n');
+	Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn dump_ast() {
+		let tree = Parser::new("a\n{{ b:>3 }}{{#c as x where x.d sort_by e desc}}{{?}}{{! n }}{{/}}{{^f}}{{=<% %>=}}").get_tpl("{{", "}}").unwrap();
+		assert_eq!(ast(&tree), r#"Literal "a\n"
+DirectSub Path([Down("b")]) format ">3" at 2:1 "{{ b:>3 }}"
+Section Path([Down("c")]) as x where Path([Down("x"), Down("d")]) sort_by [Down("e")] desc at 2:11 "{{#c as x where x.d sort_by e desc}}" to "{{/}}"
+  KeySub 0
+  Comment " n "
+InvertedSection Path([Down("f")]) at 2:65 "{{^f}}"
+  SetDelim "<%" "%>"
+"#);
+	}
+}
@@ -0,0 +1,370 @@
+use ::nom;
+use ::yaml_rust::Yaml;
+use ::errors::*;
+use ::parse::*;
+use ::yaml;
+use ::std::cmp::Ordering;
+
+// Expressions in direct substitutions, as in `{{qty * value}}` or `{{sum(orders, "value")}}`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op { Add, Sub, Mul, Div, Rem, Eq, Ne, Lt, Le, Gt, Ge, And, Or }
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+	Path(YamlPath),
+	Int(i64),
+	Real(String),
+	Str(String),
+	Meta(String), // Loop metadata, as in `@index`
+	Not(Box<Expr>),
+	Binary(Op, Box<Expr>, Box<Expr>),
+	Call(String, Vec<Expr>),
+}
+
+// The position of the current item in the section being iterated over
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loop {
+	pub index: usize,
+	pub count: usize,
+}
+
+const FUNCTIONS: &[&str] = &["sum", "count", "min", "max", "avg"];
+const META: &[&str] = &["index", "first", "last"];
+
+fn fold_ops(first: Result<Expr>, rest: Vec<(&str, Result<Expr>)>) -> Result<Expr> {
+	rest.into_iter().fold(first, |acc, (op, e)| {
+		let op = match op {
+			"+" => Op::Add, "-" => Op::Sub, "*" => Op::Mul, "/" => Op::Div, "%" => Op::Rem,
+			"==" => Op::Eq, "!=" => Op::Ne, "<" => Op::Lt, "<=" => Op::Le, ">" => Op::Gt, ">=" => Op::Ge,
+			"&&" => Op::And, _ => Op::Or,
+		};
+		Ok(Expr::Binary(op, Box::new(acc?), Box::new(e?)))
+	})
+}
+
+named!(space<&str, Option<&str>>, opt!(complete!(nom::multispace)));
+
+named!(number<&str, Result<Expr>>,
+	do_parse!(
+		int: call!(nom::digit) >>
+		frac: opt!(complete!(preceded!(char!('.'), nom::digit))) >>
+		(match frac {
+			Some(frac) => Ok(Expr::Real(format!("{}.{}", int, frac))),
+			None => int.parse::<i64>().map(Expr::Int).chain_err(|| format!("Integer {} is too large", int)),
+		})
+	)
+);
+
+named!(function<&str, Result<Expr>>,
+	do_parse!(
+		name: is_a!(KEYCHARS) >> char!('(') >> space >>
+		args: separated_list!(delimited!(space, char!(','), space), expr) >>
+		space >> char!(')') >>
+		({
+			let args = args.into_iter().collect::<Result<Vec<Expr>>>();
+			if FUNCTIONS.contains(&name) { args.map(|a| Expr::Call(name.to_string(), a)) }
+			else { Err(Error::from(format!("Unknown function {}", name))) }
+		})
+	)
+);
+
+named!(atom<&str, Result<Expr>>,
+	alt!(
+		number |
+		do_parse!(char!('"') >> s: take_until!("\"") >> char!('"') >> (Ok(Expr::Str(s.to_string())))) |
+		do_parse!(char!('(') >> space >> e: expr >> space >> char!(')') >> (e)) |
+		do_parse!(char!('@') >> name: is_a!(KEYCHARS) >> (
+			if META.contains(&name) { Ok(Expr::Meta(name.to_string())) }
+			else { Err(Error::from(format!("Unknown loop variable @{}", name))) }
+		)) |
+		function |
+		do_parse!(path: yaml_path >> (match path {
+			Ok(ref p) if p.is_empty() => Err(Error::from("Expected a value in expression")),
+			p => p.map(Expr::Path),
+		}))
+	)
+);
+
+named!(unary<&str, Result<Expr>>,
+	alt!(
+		do_parse!(char!('!') >> space >> e: unary >> (e.map(|e| Expr::Not(Box::new(e))))) |
+		atom
+	)
+);
+
+named!(product<&str, Result<Expr>>,
+	do_parse!(
+		first: unary >>
+		rest: many0!(complete!(do_parse!(space >> op: alt!(tag!("*") | tag!("/") | tag!("%")) >> space >> e: unary >> ((op, e))))) >>
+		(fold_ops(first, rest))
+	)
+);
+
+named!(sum<&str, Result<Expr>>,
+	do_parse!(
+		first: product >>
+		rest: many0!(complete!(do_parse!(space >> op: alt!(tag!("+") | tag!("-")) >> space >> e: product >> ((op, e))))) >>
+		(fold_ops(first, rest))
+	)
+);
+
+named!(comparison<&str, Result<Expr>>,
+	do_parse!(
+		first: sum >>
+		rest: opt!(complete!(do_parse!(
+			space >> op: alt!(tag!("==") | tag!("!=") | tag!("<=") | tag!(">=") | tag!("<") | tag!(">")) >> space >> e: sum >> ((op, e))
+		))) >>
+		(fold_ops(first, rest.into_iter().collect()))
+	)
+);
+
+named!(conjunction<&str, Result<Expr>>,
+	do_parse!(
+		first: comparison >>
+		rest: many0!(complete!(do_parse!(space >> op: tag!("&&") >> space >> e: comparison >> ((op, e))))) >>
+		(fold_ops(first, rest))
+	)
+);
+
+named!(pub expr<&str, Result<Expr>>,
+	do_parse!(
+		space >>
+		first: conjunction >>
+		rest: many0!(complete!(do_parse!(space >> op: tag!("||") >> space >> e: conjunction >> ((op, e))))) >>
+		space >>
+		(fold_ops(first, rest))
+	)
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Num { Int(i64), Real(f64) }
+
+impl Num {
+	fn from_yaml(yaml: &Yaml) -> Result<Self> {
+		match yaml {
+			Yaml::Integer(x) => Ok(Num::Int(*x)),
+			Yaml::Real(ref s) => ::format::real(s).map(Num::Real).ok_or_else(|| Error::from(format!("Can't use {} as a number", s))),
+			_ => bail!("Arithmetic can only be done on numbers"),
+		}
+	}
+	fn to_yaml(self) -> Yaml {
+		match self {
+			Num::Int(x) => Yaml::Integer(x),
+			Num::Real(x) if x.is_nan() => Yaml::Real(".nan".to_string()),
+			Num::Real(x) if x.is_infinite() => Yaml::Real(if x < 0.0 { "-.inf" } else { ".inf" }.to_string()),
+			Num::Real(x) => Yaml::Real(format!("{:.14e}", x).parse::<f64>().unwrap_or(x).to_string()), // To 15 significant digits, so rounding errors don't show
+		}
+	}
+	fn real(self) -> f64 {
+		match self {
+			Num::Int(x) => x as f64,
+			Num::Real(x) => x,
+		}
+	}
+	fn apply(op: Op, a: Num, b: Num) -> Result<Num> {
+		match (op, a, b) {
+			(Op::Div, _, _) | (Op::Rem, _, _) if b.real() == 0.0 => bail!("Division by zero"),
+			(Op::Div, a, b) => Ok(Num::Real(a.real() / b.real())),
+			(op, Num::Int(x), Num::Int(y)) => match op {
+				Op::Add => x.checked_add(y),
+				Op::Sub => x.checked_sub(y),
+				Op::Mul => x.checked_mul(y),
+				Op::Rem => x.checked_rem(y),
+				_ => bail!("Not an arithmetic operator"),
+			}.map(Num::Int).ok_or_else(|| Error::from("Integer overflow")),
+			(op, a, b) => Ok(Num::Real(match op {
+				Op::Add => a.real() + b.real(),
+				Op::Sub => a.real() - b.real(),
+				Op::Mul => a.real() * b.real(),
+				Op::Rem => a.real() % b.real(),
+				_ => bail!("Not an arithmetic operator"),
+			})),
+		}
+	}
+}
+
+fn aggregate(name: &str, args: Vec<Yaml>) -> Result<Yaml> {
+	let mut args = args.into_iter();
+	let items = match args.next() {
+		Some(Yaml::Array(items)) => items,
+		Some(Yaml::Hash(items)) => items.into_iter().map(|(_, v)| v).collect(),
+		_ => bail!(format!("The first argument to {} must be a sequence or mapping", name)),
+	};
+	let field = match args.next() {
+		Some(Yaml::String(ref s)) => s.split('.').map(|k| YamlPathElem::Down(k.to_string())).collect(),
+		Some(_) => bail!(format!("The second argument to {} must be a string", name)),
+		None => vec![],
+	};
+	if args.next().is_some() { bail!(format!("{} takes at most two arguments", name)); }
+	let values = items.iter().map(|item| yaml::get(item, &field)).collect::<Vec<&Yaml>>();
+	if name == "count" {
+		let n = if field.is_empty() { values.len() } else { values.iter().filter(|v| yaml::bool(v)).count() };
+		return Ok(Yaml::Integer(n as i64));
+	}
+	let nums = values.into_iter().map(Num::from_yaml).collect::<Result<Vec<Num>>>().chain_err(|| format!("Failed to compute {}", name))?;
+	let sum = nums.iter().try_fold(Num::Int(0), |acc, x| Num::apply(Op::Add, acc, *x))?;
+	Ok(match name {
+		_ if nums.is_empty() && name != "sum" => Yaml::Null,
+		"sum" => sum.to_yaml(),
+		"avg" => Num::apply(Op::Div, sum, Num::Int(nums.len() as i64))?.to_yaml(),
+		"min" => nums.into_iter().fold(None, |acc: Option<Num>, x| match acc { Some(m) if m.real() <= x.real() => Some(m), _ => Some(x) }).map_or(Yaml::Null, Num::to_yaml),
+		_ => nums.into_iter().fold(None, |acc: Option<Num>, x| match acc { Some(m) if m.real() >= x.real() => Some(m), _ => Some(x) }).map_or(Yaml::Null, Num::to_yaml),
+	})
+}
+
+fn compare(op: Op, a: &Yaml, b: &Yaml) -> Result<bool> {
+	let ord = yaml::compare(a, b);
+	Ok(match (op, ord) {
+		(Op::Eq, _) => ord.map_or(a == b, |o| o == Ordering::Equal),
+		(Op::Ne, _) => ord.map_or(a != b, |o| o != Ordering::Equal),
+		(_, None) if [a, b].iter().any(|y| matches!(y, Yaml::Null | Yaml::BadValue)) => false, // Missing values are never ordered
+		(_, None) => bail!("Only numbers, strings, and booleans of the same kind can be compared"),
+		(Op::Lt, Some(o)) => o == Ordering::Less,
+		(Op::Le, Some(o)) => o != Ordering::Greater,
+		(Op::Gt, Some(o)) => o == Ordering::Greater,
+		(_, Some(o)) => o != Ordering::Less,
+	})
+}
+
+pub fn substitute(expr: &Expr, f: &dyn Fn(&Expr) -> Option<Expr>) -> Expr { // Replaces the paths and loop variables f gives expressions for
+	match expr {
+		Expr::Path(_) | Expr::Meta(_) => f(expr).unwrap_or_else(|| expr.clone()),
+		Expr::Not(ref e) => Expr::Not(Box::new(substitute(e, f))),
+		Expr::Binary(op, ref a, ref b) => Expr::Binary(*op, Box::new(substitute(a, f)), Box::new(substitute(b, f))),
+		Expr::Call(ref name, ref args) => Expr::Call(name.to_string(), args.iter().map(|a| substitute(a, f)).collect()),
+		e => e.clone(),
+	}
+}
+
+fn negative(x: Expr) -> Expr { // There's no unary minus
+	Expr::Binary(Op::Sub, Box::new(Expr::Int(0)), Box::new(x))
+}
+
+pub fn literal(value: &Yaml) -> Option<Expr> { // An expression that evaluates to the value, if there is one
+	match value {
+		Yaml::String(ref s) if ! s.contains('"') => Some(Expr::Str(s.to_string())),
+		Yaml::Integer(x) if *x < 0 => x.checked_neg().map(|x| negative(Expr::Int(x))),
+		Yaml::Integer(x) => Some(Expr::Int(*x)),
+		Yaml::Real(ref x) if x.split('.').count() == 2 && x.split('.').all(|d| ! d.is_empty() && d.chars().all(|c| c.is_ascii_digit())) => Some(Expr::Real(x.to_string())),
+		Yaml::Real(ref x) => match ::format::real(x) { // Exponents and signs are written out, but infinity and NaN can't be
+			Some(x) if x.is_finite() => {
+				let digits = x.abs().to_string();
+				let real = Expr::Real(if digits.contains('.') { digits } else { digits + ".0" });
+				Some(if x < 0.0 { negative(real) } else { real })
+			},
+			_ => None,
+		},
+		Yaml::Boolean(false) => Some(Expr::Not(Box::new(Expr::Int(0)))), // There are no boolean literals, but numbers are true
+		Yaml::Boolean(true) => Some(Expr::Not(Box::new(Expr::Not(Box::new(Expr::Int(0)))))),
+		_ => None,
+	}
+}
+
+pub fn uses_loop(expr: &Expr) -> bool {
+	match expr {
+		Expr::Meta(_) => true,
+		Expr::Not(ref e) => uses_loop(e),
+		Expr::Binary(_, ref a, ref b) => uses_loop(a) || uses_loop(b),
+		Expr::Call(_, ref args) => args.iter().any(uses_loop),
+		_ => false,
+	}
+}
+
+pub fn eval(values: &Yaml, expr: &Expr, resolve: &dyn Fn(&YamlPath) -> YamlPath, iteration: Option<Loop>) -> Result<Yaml> {
+	let eval = |e| eval(values, e, resolve, iteration);
+	match expr {
+		Expr::Path(ref path) => Ok(yaml::get(values, &resolve(path)).clone()), // Missing values are errors only where they're used
+		Expr::Int(x) => Ok(Yaml::Integer(*x)),
+		Expr::Real(ref x) => Ok(Yaml::Real(x.to_string())),
+		Expr::Str(ref s) => Ok(Yaml::String(s.to_string())),
+		Expr::Meta(ref name) => {
+			let cur = iteration.ok_or_else(|| Error::from(format!("@{} can only be used inside a section", name)))?;
+			Ok(match &name[..] {
+				"index" => Yaml::Integer(cur.index as i64),
+				"first" => Yaml::Boolean(cur.index == 0),
+				_ => Yaml::Boolean(cur.index + 1 == cur.count),
+			})
+		},
+		Expr::Not(ref e) => Ok(Yaml::Boolean(! yaml::bool(&eval(e)?))),
+		Expr::Binary(Op::And, ref a, ref b) => Ok(Yaml::Boolean(yaml::bool(&eval(a)?) && yaml::bool(&eval(b)?))),
+		Expr::Binary(Op::Or, ref a, ref b) => Ok(Yaml::Boolean(yaml::bool(&eval(a)?) || yaml::bool(&eval(b)?))),
+		Expr::Binary(op, ref a, ref b) => match (*op, eval(a)?, eval(b)?) {
+			(Op::Eq, a, b) | (Op::Ne, a, b) | (Op::Lt, a, b) | (Op::Le, a, b) | (Op::Gt, a, b) | (Op::Ge, a, b) => Ok(Yaml::Boolean(compare(*op, &a, &b)?)),
+			(Op::Add, Yaml::String(a), Yaml::String(b)) => Ok(Yaml::String(a + &b)),
+			(op, a, b) => Ok(Num::apply(op, Num::from_yaml(&a)?, Num::from_yaml(&b)?)?.to_yaml()),
+		},
+		Expr::Call(ref name, ref args) => aggregate(name, args.iter().map(eval).collect::<Result<Vec<Yaml>>>()?),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::yaml_rust::YamlLoader;
+	fn parse(s: &str) -> Result<Expr> {
+		let input = format!("{}}}", s);
+		let (rest, ret) = expr(&input).unwrap();
+		assert_eq!(rest, "}");
+		ret
+	}
+	fn path(s: &str) -> Expr {
+		Expr::Path(s.split('.').map(|x| YamlPathElem::Down(x.to_string())).collect())
+	}
+	#[test]
+	fn expr_parse() {
+		let bin = |op, a, b| Expr::Binary(op, Box::new(a), Box::new(b));
+		assert_eq!(parse("a.b").unwrap(), path("a.b"));
+		assert_eq!(parse(" qty * value ").unwrap(), bin(Op::Mul, path("qty"), path("value")));
+		assert_eq!(parse("1 + 2*3 - x-y").unwrap(), bin(Op::Sub, bin(Op::Add, Expr::Int(1), bin(Op::Mul, Expr::Int(2), Expr::Int(3))), path("x-y")));
+		assert_eq!(parse("(1 + 2.5) / 2 % 3").unwrap(), bin(Op::Rem, bin(Op::Div, bin(Op::Add, Expr::Int(1), Expr::Real("2.5".to_string())), Expr::Int(2)), Expr::Int(3)));
+		assert_eq!(parse("sum(orders, \"value\")").unwrap(), Expr::Call("sum".to_string(), vec![path("orders"), Expr::Str("value".to_string())]));
+		assert_eq!(parse("count( .x )").unwrap(), Expr::Call("count".to_string(), vec![Expr::Path(vec![YamlPathElem::Root, YamlPathElem::Down("x".to_string())])]));
+		assert_eq!(parse("!a || b && c.d >= 2 + 1").unwrap(), bin(Op::Or, Expr::Not(Box::new(path("a"))), bin(Op::And, path("b"), bin(Op::Ge, path("c.d"), bin(Op::Add, Expr::Int(2), Expr::Int(1))))));
+		assert_eq!(parse("@index != 0").unwrap(), bin(Op::Ne, Expr::Meta("index".to_string()), Expr::Int(0)));
+		assert!(parse("@nope").is_err());
+		assert!(parse("nope(x)").is_err());
+		assert!(parse("99999999999999999999").is_err());
+	}
+	#[test]
+	fn expr_eval() {
+		let doc = &YamlLoader::load_from_str("
+orders:
+  - {qty: 3, value: 46.20, paid: false}
+  - {qty: 1, value: 24.00, paid: true}
+  - {qty: 12, value: 922.36, paid: false}
+n: 7
+s: abc
+low: -.Inf
+odd: .nan
+").unwrap()[0];
+		let eval = |s: &str| eval(doc, &parse(s).unwrap(), &|p| p.to_vec(), Some(Loop { index: 1, count: 3 }));
+		assert_eq!(eval("n * 2 + 1").unwrap(), Yaml::Integer(15));
+		assert_eq!(eval("n / 2").unwrap(), Yaml::Real("3.5".to_string()));
+		assert_eq!(eval("n % 4").unwrap(), Yaml::Integer(3));
+		assert_eq!(eval("orders.0.qty * orders.0.value").unwrap(), Yaml::Real("138.6".to_string()));
+		assert_eq!(eval("orders.1.value / 3 + 0.2").unwrap(), Yaml::Real("8.2".to_string()));
+		assert_eq!(eval("0.1 + 0.2").unwrap(), Yaml::Real("0.3".to_string()));
+		assert_eq!(eval("low * 2").unwrap(), Yaml::Real("-.inf".to_string()));
+		assert_eq!(eval("odd + 1").unwrap(), Yaml::Real(".nan".to_string()));
+		assert_eq!(eval("s + \"def\"").unwrap(), Yaml::String("abcdef".to_string()));
+		assert_eq!(eval("sum(orders, \"qty\")").unwrap(), Yaml::Integer(16));
+		assert_eq!(eval("count(orders)").unwrap(), Yaml::Integer(3));
+		assert_eq!(eval("count(orders, \"paid\")").unwrap(), Yaml::Integer(1));
+		assert_eq!(eval("min(orders, \"value\")").unwrap(), Yaml::Real("24".to_string()));
+		assert_eq!(eval("max(orders, \"qty\")").unwrap(), Yaml::Integer(12));
+		assert_eq!(eval("avg(orders, \"qty\") * 3").unwrap(), Yaml::Real("16".to_string()));
+		assert!(eval("sum(orders, \"missing.x\") - 1").is_err());
+		assert!(eval("max(n)").is_err());
+		assert_eq!(eval("n > 6.5 && s == \"abc\"").unwrap(), Yaml::Boolean(true));
+		assert_eq!(eval("!orders.0.paid || missing.x").unwrap(), Yaml::Boolean(true));
+		assert_eq!(eval("n == s").unwrap(), Yaml::Boolean(false));
+		assert_eq!(eval("@index").unwrap(), Yaml::Integer(1));
+		assert_eq!(eval("@first || @last").unwrap(), Yaml::Boolean(false));
+		assert_eq!(eval("missing < 1 || 1 < missing").unwrap(), Yaml::Boolean(false));
+		assert!(eval("n < s").is_err());
+		assert!(eval("n / 0").is_err());
+		assert!(eval("s * 2").is_err());
+		assert!(eval("missing + 1").is_err());
+	}
+}
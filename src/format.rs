@@ -0,0 +1,256 @@
+use ::nom;
+use ::yaml_rust::Yaml;
+use ::errors::*;
+
+// Format specifiers for direct substitutions, as in `{{value:>10,.2f}}`.  The syntax follows Python's format specification
+// mini-language: [[fill]align][sign][$][#][0][width][grouping][.precision][type].
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Format {
+	pub fill: Option<char>,
+	pub align: Option<char>, // One of <>^=
+	pub sign: Option<char>, // One of +-<space>
+	pub currency: bool,
+	pub alternate: bool,
+	pub zero: bool,
+	pub width: Option<usize>,
+	pub grouping: Option<char>, // One of ,_
+	pub precision: Option<usize>,
+	pub kind: Option<char>, // One of dfeExXob%s
+}
+
+impl ::std::fmt::Display for Format { // The specification as it would be written
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		if let Some(align) = self.align { write!(f, "{}{}", self.fill.map(|c| c.to_string()).unwrap_or_default(), align)?; }
+		if let Some(sign) = self.sign { write!(f, "{}", sign)?; }
+		if self.currency { write!(f, "$")?; }
+		if self.alternate { write!(f, "#")?; }
+		if self.zero { write!(f, "0")?; }
+		if let Some(width) = self.width { write!(f, "{}", width)?; }
+		if let Some(grouping) = self.grouping { write!(f, "{}", grouping)?; }
+		if let Some(precision) = self.precision { write!(f, ".{}", precision)?; }
+		if let Some(kind) = self.kind { write!(f, "{}", kind)?; }
+		Ok(())
+	}
+}
+
+fn parse_usize(digits: Option<&str>) -> Result<Option<usize>> {
+	digits.map(|d| d.parse::<usize>().chain_err(|| format!("Format number {} is too large", d))).map_or(Ok(None), |r| r.map(Some))
+}
+
+named!(pub format_spec<&str, Result<Format>>,
+	do_parse!(
+		align: opt!(complete!(alt!(
+			do_parse!(fill: call!(nom::anychar) >> align: one_of!("<>^=") >> ((Some(fill), align))) |
+			do_parse!(align: one_of!("<>^=") >> ((None, align)))
+		))) >>
+		sign: opt!(complete!(one_of!("+- "))) >>
+		currency: opt!(complete!(char!('$'))) >>
+		alternate: opt!(complete!(char!('#'))) >>
+		zero: opt!(complete!(char!('0'))) >>
+		width: opt!(complete!(nom::digit)) >>
+		grouping: opt!(complete!(one_of!(",_"))) >>
+		precision: opt!(complete!(preceded!(char!('.'), nom::digit))) >>
+		kind: opt!(complete!(one_of!("dfeExXob%s"))) >>
+		({
+			let (width, precision) = (parse_usize(width), parse_usize(precision));
+			width.and_then(|width| precision.map(|precision| Format {
+				fill: align.and_then(|a| a.0),
+				align: align.map(|a| a.1),
+				sign,
+				currency: currency.is_some(),
+				alternate: alternate.is_some(),
+				zero: zero.is_some(),
+				width,
+				grouping,
+				precision,
+				kind,
+			}))
+		})
+	)
+);
+
+pub fn real(s: &str) -> Option<f64> { // Including YAML's special values, which Rust doesn't read
+	match s {
+		".inf" | ".Inf" | ".INF" | "+.inf" | "+.Inf" | "+.INF" => Some(f64::INFINITY),
+		"-.inf" | "-.Inf" | "-.INF" => Some(f64::NEG_INFINITY),
+		".nan" | ".NaN" | ".NAN" => Some(f64::NAN),
+		_ => s.parse::<f64>().ok(),
+	}
+}
+
+fn group(digits: &str, sep: char, size: usize) -> String {
+	let mut ret = String::new();
+	for (i, c) in digits.chars().enumerate() {
+		if i > 0 && (digits.len() - i).is_multiple_of(size) { ret.push(sep); }
+		ret.push(c);
+	}
+	ret
+}
+
+impl Format {
+	fn float(&self, x: f64) -> Result<String> { // x must not be negative
+		let precision = self.precision.unwrap_or(6);
+		if ! x.is_finite() { // Written as Python does, whatever the precision
+			let s = if x.is_nan() { "nan" } else { "inf" };
+			return Ok(match self.kind {
+				None | Some('f') | Some('e') => s.to_string(),
+				Some('E') => s.to_uppercase(),
+				Some('%') => format!("{}%", s),
+				Some(k) => bail!(format!("Format type {} can't be used with real numbers", k)),
+			});
+		}
+		Ok(match self.kind {
+			None | Some('f') => format!("{:.*}", precision, x),
+			Some('%') => format!("{:.*}%", precision, x * 100.0),
+			Some(k) if k == 'e' || k == 'E' => {
+				let s = format!("{:.*e}", precision, x);
+				let (mantissa, exp) = s.split_at(s.find('e').unwrap_or(s.len())); // Rust always includes an exponent here
+				let exp = exp.trim_start_matches('e').parse::<i32>().unwrap_or(0);
+				format!("{}{}{}{:02}", mantissa, k, if exp < 0 { '-' } else { '+' }, exp.abs())
+			},
+			Some(k) => bail!(format!("Format type {} can't be used with real numbers", k)),
+		})
+	}
+	fn number(&self, yaml: &Yaml) -> Result<(bool, String)> { // Returns whether the number is negative, and its formatted absolute value
+		match (yaml, self.kind) {
+			(Yaml::Integer(x), kind) => {
+				let abs = x.unsigned_abs();
+				Ok((*x < 0, match kind {
+					None | Some('d') => abs.to_string(),
+					Some('x') => format!("{:x}", abs),
+					Some('X') => format!("{:X}", abs),
+					Some('o') => format!("{:o}", abs),
+					Some('b') => format!("{:b}", abs),
+					Some(_) => self.float(abs as f64)?,
+				}))
+			},
+			(Yaml::Real(ref s), kind) => {
+				let x = real(s).ok_or_else(|| Error::from(format!("Can't format {} as a number", s)))?;
+				let body = match (kind, self.precision) {
+					(None, None) if x.is_finite() => s.trim_start_matches(['-', '+']).to_string(), // Keep the number as it was written
+					_ => self.float(x.abs())?,
+				};
+				Ok((x.is_sign_negative(), body))
+			},
+			_ => bail!("Numeric formats can only be used with numbers"),
+		}
+	}
+	pub fn apply(&self, yaml: &Yaml) -> Result<String> {
+		let numeric = match yaml {
+			Yaml::Integer(_) | Yaml::Real(_) => self.kind != Some('s'),
+			_ => self.kind.is_some() && self.kind != Some('s'),
+		};
+		let (prefix, body) = if numeric {
+			let (negative, body) = self.number(yaml)?;
+			let radix = matches!(self.kind, Some('x') | Some('X') | Some('o') | Some('b'));
+			let body = match (self.grouping, radix) {
+				(Some(','), true) => bail!("Only _ can be used for grouping with this format type"),
+				(Some(sep), _) => {
+					let end = if radix { body.len() } else { body.find(|c: char| ! c.is_ascii_digit()).unwrap_or(body.len()) };
+					format!("{}{}", group(&body[..end], sep, if radix { 4 } else { 3 }), &body[end..])
+				},
+				(None, _) => body,
+			};
+			let sign = match (negative, self.sign) {
+				(true, _) => "-",
+				(false, Some('+')) => "+",
+				(false, Some(' ')) => " ",
+				_ => "",
+			};
+			let alternate = match (self.alternate, self.kind) {
+				(true, Some('x')) => "0x",
+				(true, Some('X')) => "0X",
+				(true, Some('o')) => "0o",
+				(true, Some('b')) => "0b",
+				_ => "",
+			};
+			(format!("{}{}{}", sign, if self.currency { "$" } else { "" }, alternate), body)
+		}
+		else {
+			if self.sign.is_some() || self.currency || self.alternate || self.grouping.is_some() { bail!("String formats can't include signs, currency, alternate forms, or grouping"); }
+			let s = match yaml {
+				Yaml::Real(ref x) | Yaml::String(ref x) => x.to_string(),
+				Yaml::Integer(x) => x.to_string(),
+				Yaml::Boolean(x) => x.to_string(),
+				Yaml::Null => "".to_string(),
+				_ => bail!("Can't stringify type"),
+			};
+			("".to_string(), match self.precision { Some(n) => s.chars().take(n).collect(), None => s })
+		};
+		let align = self.align.unwrap_or(if self.zero { '=' } else if numeric { '>' } else { '<' });
+		let fill = self.fill.unwrap_or(if self.zero && self.align.is_none() { '0' } else { ' ' }).to_string();
+		let pad = self.width.unwrap_or(0).saturating_sub(prefix.chars().count() + body.chars().count());
+		Ok(match align {
+			'<' => format!("{}{}{}", prefix, body, fill.repeat(pad)),
+			'^' => format!("{}{}{}{}", fill.repeat(pad / 2), prefix, body, fill.repeat(pad - pad / 2)),
+			'=' => format!("{}{}{}", prefix, fill.repeat(pad), body),
+			_ => format!("{}{}{}", fill.repeat(pad), prefix, body),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn fmt(spec: &str, yaml: Yaml) -> Result<String> {
+		let input = format!("{}}}", spec);
+		let (rest, format) = format_spec(&input).unwrap();
+		assert_eq!(rest, "}");
+		format?.apply(&yaml)
+	}
+	#[test]
+	fn format_spec_basic() {
+		assert_eq!(format_spec("}").unwrap().1.unwrap(), Format::default());
+		assert_eq!(format_spec("*^+$#012,.3f}").unwrap().1.unwrap(), Format {
+			fill: Some('*'), align: Some('^'), sign: Some('+'), currency: true, alternate: true, zero: true,
+			width: Some(12), grouping: Some(','), precision: Some(3), kind: Some('f'),
+		});
+		assert_eq!(format_spec("<5}").unwrap().1.unwrap(), Format { align: Some('<'), width: Some(5), ..Format::default() });
+		assert!(format_spec("99999999999999999999999}").unwrap().1.is_err());
+	}
+	#[test]
+	fn format_numbers() {
+		let real = |s: &str| Yaml::Real(s.to_string());
+		assert_eq!(fmt(".2f", real("46.2")).unwrap(), "46.20");
+		assert_eq!(fmt(",.2f", real("23201.66")).unwrap(), "23,201.66");
+		assert_eq!(fmt("$,.2f", real("-1234.5")).unwrap(), "-$1,234.50");
+		assert_eq!(fmt("", real("46.20")).unwrap(), "46.20");
+		assert_eq!(fmt(",", real("-1234.50")).unwrap(), "-1,234.50");
+		assert_eq!(fmt(">5", Yaml::Integer(9)).unwrap(), "    9");
+		assert_eq!(fmt("5", Yaml::Integer(-9)).unwrap(), "   -9");
+		assert_eq!(fmt("05", Yaml::Integer(-9)).unwrap(), "-0009");
+		assert_eq!(fmt("08.2f", real("3.14159")).unwrap(), "00003.14");
+		assert_eq!(fmt("+d", Yaml::Integer(1000000)).unwrap(), "+1000000");
+		assert_eq!(fmt(",d", Yaml::Integer(1000000)).unwrap(), "1,000,000");
+		assert_eq!(fmt("x", Yaml::Integer(255)).unwrap(), "ff");
+		assert_eq!(fmt("#X", Yaml::Integer(255)).unwrap(), "0XFF");
+		assert_eq!(fmt("#010b", Yaml::Integer(5)).unwrap(), "0b00000101");
+		assert_eq!(fmt("_b", Yaml::Integer(255)).unwrap(), "1111_1111");
+		assert_eq!(fmt("o", Yaml::Integer(8)).unwrap(), "10");
+		assert_eq!(fmt(".1%", real("0.256")).unwrap(), "25.6%");
+		assert_eq!(fmt(".2e", Yaml::Integer(12345)).unwrap(), "1.23e+04");
+		assert_eq!(fmt("E", real("0.0001")).unwrap(), "1.000000E-04");
+		assert_eq!(fmt("*^7.1f", real("2.25")).unwrap(), "**2.2**");
+		assert_eq!(fmt(".2f", real(".inf")).unwrap(), "inf");
+		assert_eq!(fmt("+", real("-.inf")).unwrap(), "-inf");
+		assert_eq!(fmt(">6E", real(".Inf")).unwrap(), "   INF");
+		assert_eq!(fmt("+.1%", real(".nan")).unwrap(), "+nan%");
+		assert_eq!(fmt("06,", real(".NaN")).unwrap(), "000nan");
+		assert!(fmt("x", real("1.5")).is_err());
+		assert!(fmt("", real("1.2.3")).is_err());
+		assert!(fmt(",x", Yaml::Integer(1)).is_err());
+		assert!(fmt("d", Yaml::String("1".to_string())).is_err());
+	}
+	#[test]
+	fn format_strings() {
+		assert_eq!(fmt("<6", Yaml::String("abc".to_string())).unwrap(), "abc   ");
+		assert_eq!(fmt(">6", Yaml::String("abc".to_string())).unwrap(), "   abc");
+		assert_eq!(fmt("-^7", Yaml::String("abc".to_string())).unwrap(), "--abc--");
+		assert_eq!(fmt(".2", Yaml::String("abc".to_string())).unwrap(), "ab");
+		assert_eq!(fmt("6s", Yaml::Integer(12)).unwrap(), "12    ");
+		assert_eq!(fmt("3", Yaml::Null).unwrap(), "   ");
+		assert!(fmt("+", Yaml::String("abc".to_string())).is_err());
+		assert!(fmt("5", Yaml::Array(vec![])).is_err());
+	}
+}
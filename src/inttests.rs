@@ -3,10 +3,12 @@ use ::parse;
 use ::yaml;
 
 fn check_render(values: Vec<&str>, template: &str, expected: &str) {
-	let mut val = yaml::merge(values.into_iter().flat_map(|s| yaml_rust::YamlLoader::load_from_str(&s).unwrap().into_iter()).collect());
+	let mut val = yaml::merge(values.into_iter().flat_map(|s| yaml_rust::YamlLoader::load_from_str(s).unwrap().into_iter()).collect()).unwrap();
 	let args = ::ParseArgs::from_yaml(&mut val).unwrap();
+	::schema::validate(&mut val).unwrap();
+	if args.sort_keys { yaml::sort_keys(&mut val); }
 	let tpl = &parse::Parser::new(template).get_tpl(&args.open, &args.close).unwrap();
-	assert_eq!(::render(&val, tpl, &vec![], args.ignore).unwrap(), expected);
+	assert_eq!(::render(&val, tpl, &vec![], &::Scope::default(), args.ignore).unwrap(), expected);
 }
 
 #[test]
@@ -51,6 +53,35 @@ x:
 "#;
 	check_render(vec![truefalse], "{{#x}}{{#k}}{{&.t}}{{/}}{{^k}}{{&.f}}{{/}}{{/}}", "abcdmnop");
 	check_render(vec!["_config:\n  ignore: true"], "{{x}}", "");
+	check_render(vec!["x: a"], "{{x}}{{=<%=}}<%x%>{{x}}<%={{ }}=%>{{x}}", "aa{{x}}a");
+	check_render(vec!["x:\n  - y: [1, 2]\n    z: a\n  - y: [3]\n    z: b"], "{{#x as i}}{{#i.y as j}}{{j}}{{i.z}}{{/}}{{/}}", "1a2a3b");
+	check_render(vec!["x: {y: 1, z: 2}\ny: 3"], "{{y}}{{#with x as v}}{{v.y}}{{let y = v.z}}{{y}}{{.y}}{{/}}{{y}}", "31233");
+	check_render(vec!["x: [a, b]\nc: d"], "{{#x as c}}{{c}}{{.c}}{{?}}{{/}}{{c}}", "ad0bd1d");
+	check_render(vec!["a: {s: x, c: y}\nb: {s: z, c: w}"], "{{*call addr a}}|{{*define addr}}{{s}}, {{c}}{{/}}{{*call addr b}}", "x, y|z, w");
+	check_render(vec!["a: 1\nb: [2, 3]"], "{{*define m}}{{x}}{{#y}}{{}}{{/}}{{?}}{{/}}{{#b}}{{*call m x=.a y=.b}};{{/}}", "1230;1231;");
+	check_render(vec!["t: {v: a, c: [{v: b, c: [{v: c}]}]}"], "{{*define tree}}({{v}}{{#c}}{{*call tree}}{{/}}){{/}}{{*call tree t}}", "(a(b(c)))");
+	check_render(vec!["x: 46.2\ny: 23201.66\nz: 7"], "{{x:.2f}}|{{y:,.2f}}|{{z:>3}}|{{z:03}}|{{z:#b}}|", "46.20|23,201.66|  7|007|0b111|");
+	check_render(vec!["o: [{q: 3, v: 46.20}, {q: 1, v: 24.00}, {q: 12, v: 922.36}]"], "{{#o}}{{q * v:.2f}} {{/}}{{sum(o, \"v\"):,.2f}} {{count(o)}} {{o.0.q * (2 + 1)}}", "138.60 24.00 11068.32 992.56 3 9");
+	check_render(vec!["_config:\n  ignore: true\nx: a"], "{{x * 2}}|{{y + 1}}", "|");
+	check_render(vec!["_config:\n  ignore: keep\nx: a\nl: [1]"], "{{x}} {{ y }} {{y:>3}} {{l}} {{x * 2}} {{.Values.z}}", "a {{ y }} {{y:>3}} {{l}} {{x * 2}} {{.Values.z}}");
+	check_render(vec!["_config:\n  ignore: keep\nx: a"], "{{#y}}{{z}} {{.x}}{{/}}|{{^y}}none{{/}}|{{#x}}{{}}{{/}}|{{#y}}{{=<% %>=}}<%/%>", "{{#y}}{{z}} a{{/}}|{{^y}}none{{/}}|a|{{#y}}<%/%>");
+	check_render(vec!["_config:\n  ignore: keep\nx: []"], "{{#x}}a{{/}}{{^x}}b{{/}}{{#y == 1}}c{{/}}{{#x && y}}d{{/}}", "b{{#y == 1}}c{{/}}");
+	check_render(vec!["x: {c: 1, a: 2, b: 3}", "x: {a: 4, d: 5}"], "{{#x}}{{?}}{{}}{{/}}", "c1a4b3d5");
+	check_render(vec!["_config:\n  sort_keys: true\nx: {c: 1, a: 2, b: 3}", "x: {a: 4, d: 5}"], "{{#x}}{{?}}{{}}{{/}}", "a4b3c1d5");
+	check_render(vec!["h: [a, b]\nx: {y: 1}", "_config: {merge: replace}\n_merge: {x: delete}\nh: [c]"], "{{#h}}{{}}{{/}}{{^x}}-{{/}}", "c-");
+	check_render(vec!["_schema:\n  env: {enum: [dev, prod], default: dev}\n  n.*.w: {default: 1}\nn: [{w: 2}, {}]"], "{{env}}{{#n}}{{w}}{{/}}", "dev21");
+	check_render(vec!["x: a"], "{{%raw}}{{x}}{{%endraw}} {{%open}}x{{%close}} {{x}}", "{{x}} {{x}} a");
+}
+
+#[test]
+fn section_modifiers() {
+	let orders = "o:\n  - {n: a, v: 3, p: true}\n  - {n: b, v: 1, p: false}\n  - {n: c, v: 2, p: false}\n  - {n: d, p: false}";
+	check_render(vec![orders], "{{#o where !p}}{{n}}{{^@last}}, {{/}}{{/}}", "b, c, d");
+	check_render(vec![orders], "{{#o as x sort_by x.v desc limit 3}}{{@index}}{{n}}{{/}}|{{#o sort_by v}}{{n}}{{/}}", "0a1c2b|bcad");
+	check_render(vec![orders], "{{^o where v > 5}}none{{/}}{{^o where p}}never{{/}}{{#o limit 2}}{{#.o where v == @index + 1}}{{n}}{{/}}{{/}}", "nonebc");
+	check_render(vec!["x: {b: {v: 2}, a: {v: 1}}"], "{{#x where v > 1}}{{?}}{{/}}{{#x limit 1}}{{#@first}}{{?}}{{/}}{{/}}", "bb");
+	check_render(vec!["n: [3, 1, 2]"], "{{#n as x where x > 1}}{{x}}{{/}}|{{#n as x sort_by x desc limit 2}}{{x}}{{/}}", "32|32");
+	check_render(vec!["o: [{n: a, p: true}, {n: b, p: false}]"], "{{#o}}{{^p}}{{&.n}}{{/}}{{/}}|{{#o where !p}}{{n}}{{/}}", "b|b");
 }
 
 #[test]
@@ -60,5 +91,54 @@ fn the_big_one() {
 	let mut parser = parse::Parser::new(&input);
 	let val = parser.get_yaml().unwrap().unwrap().into_iter().next().unwrap();
 	let tpl = parser.get_tpl("{{", "}}").unwrap();
-	assert_eq!(::render(&val, &tpl, &vec![], false).unwrap(), expected);
+	assert_eq!(::render(&val, &tpl, &vec![], &::Scope::default(), ::Ignore::Fail).unwrap(), expected);
+}
+
+#[test]
+fn formatting() { // Formatting mustn't change what a template renders, and formatted templates stay as they are
+	let mut parser = parse::Parser::new(&::read_file("test/test.tpl").unwrap());
+	let val = parser.get_yaml().unwrap().unwrap().into_iter().next().unwrap();
+	let formatted = ::print::tree(&parser.get_tpl("{{", "}}").unwrap(), "{{", "}}");
+	let tpl = parse::Parser::new(&formatted).get_tpl("{{", "}}").unwrap();
+	assert_eq!(::print::tree(&tpl, "{{", "}}"), formatted);
+	assert_eq!(::render(&val, &tpl, &vec![], &::Scope::default(), ::Ignore::Fail).unwrap(), ::read_file("test/test.out").unwrap());
+}
+
+fn check_partial(first: &str, rest: &str, template: &str, expected: &str) { // The output of each stage must also render to the same as all values at once
+	let load = |s: &str| yaml_rust::YamlLoader::load_from_str(s).unwrap().remove(0);
+	let render = |val: &yaml_rust::Yaml, template: &str, partial| {
+		let scope = ::Scope { partial, ..::Scope::default() };
+		::render(val, &parse::Parser::new(template).get_tpl("{{", "}}").unwrap(), &vec![], &scope, ::Ignore::Fail).unwrap()
+	};
+	let output = render(&load(first), template, Some(("{{", "}}")));
+	assert_eq!(output, expected);
+	let all = yaml::merge(vec![load(first), load(rest)]).unwrap();
+	assert_eq!(render(&load(rest), &output, None), render(&all, template, None));
+}
+
+#[test]
+fn partial_render() {
+	check_partial("a: A", "b: B", "{{a}} {{b}} {{b:>3}} {{a + b}}", "A {{.b}} {{.b:>3}} {{\"A\" + .b}}");
+	check_partial("x: X", "y: [{z: 1}, {z: 2}]", "{{#y}}{{z}} {{.x}} {{@index}}{{?}}{{/}}{{^y}}none{{/}}", "{{#.y}}{{z}} X {{@index}}{{?}}{{/}}{{^.y}}none{{/}}");
+	check_partial("h: {a: {n: A}, b: {n: B}}", "c: [{k: 1}, {k: 2}]\nh: {a: {q: x}, b: {q: y}}", "{{#h as i}}{{#.c where k > 1}}{{k}}{{i.n}}{{i.q}}{{/}};{{/}}", "{{#.c where k > 1}}{{k}}A{{.h.a.q}}{{/}};{{#.c where k > 1}}{{k}}B{{.h.b.q}}{{/}};");
+	check_partial("v: 1", "w: [{p: 2}, {p: 1}]", "{{#w as g where g.p > .v}}{{g.p}}{{/}}{{#w.0.p == 2}}two{{/}}", "{{#.w as g where p > 1}}{{p}}{{/}}{{#.w.0.p == 2}}two{{/}}");
+	check_partial("x: [a, b]", "y: 1\nz: true", "{{#x}}{{@index + .y}}{{#@first && .z}}f{{/}}{{/}}", "{{0 + .y}}{{#!!0 && .z}}f{{/}}{{1 + .y}}");
+	check_partial("t: '{{'", "u: 1", "{{t}}{{%open}} {{u}}", "{{%open}}{{%open}} {{.u}}");
+	check_partial("c: 1e3\nd: -2.5\nn: -3\ne: .inf", "x: 1\ne: .inf", "{{c + x}} {{d + x}} {{n + x}} {{e == x}}", "{{1000.0 + .x}} {{0 - 2.5 + .x}} {{0 - 3 + .x}} {{.e == .x}}");
+}
+
+#[test]
+fn tracing() {
+	let val = yaml_rust::YamlLoader::load_from_str("a: [{p: true}, {}]").unwrap().remove(0);
+	let trace = ::std::cell::RefCell::new(::trace::Trace::default());
+	let scope = ::Scope { trace: Some(&trace), ..::Scope::default() };
+	let tpl = parse::Parser::new("{{#a}}{{^p}}x{{/}}{{/}}").get_tpl("{{", "}}").unwrap();
+	assert_eq!(::render(&val, &tpl, &vec![], &scope, ::Ignore::Fail).unwrap(), "x");
+	assert_eq!(trace.borrow().lines("t"), "t:1:1: a -> a = a sequence of 2 items\nt:1:1: {{#a}} iterated 2 times\n\
+		t:1:7: p -> a.0.p = true\nt:1:7: {{^p}} skipped\nt:1:7: p -> a.1.p = missing\nt:1:7: {{^p}} taken\n");
+	let trace = ::std::cell::RefCell::new(::trace::Trace::default());
+	let scope = ::Scope { trace: Some(&trace), ..::Scope::default() };
+	let tpl = parse::Parser::new("{{#with a.0 as i}}{{let j = a.1}}{{/}}").get_tpl("{{", "}}").unwrap();
+	::render(&val, &tpl, &vec![], &scope, ::Ignore::Fail).unwrap();
+	assert_eq!(trace.borrow().lines("t"), "t:1:1: a.0 -> a.0 = a mapping of 1 keys\nt:1:19: a.1 -> a.1 = a mapping of 0 keys\n");
 }
@@ -0,0 +1,341 @@
+use ::std::cell::RefCell;
+use ::regex::Regex;
+use ::yaml_rust::Yaml;
+use ::errors::*;
+
+// Validates values against a JSON Schema (draft 2020-12) document.  The schema is read with the YAML loader, which also accepts
+// JSON.  All of the validation keywords are supported except `unevaluatedItems` and `unevaluatedProperties`, and `$ref` can only
+// refer to locations or `$anchor`s within the same document.  Annotations such as `title` and `format` are ignored.
+
+const UNSUPPORTED: &[&str] = &["unevaluatedItems", "unevaluatedProperties", "$dynamicRef", "$recursiveRef"];
+
+fn show_path(path: &[String]) -> String { // Paths are written as in templates, with `.` for the root
+	if path.is_empty() { ".".to_string() } else { path.join(".") }
+}
+
+fn show_value(yaml: &Yaml) -> String {
+	match yaml {
+		Yaml::String(s) => format!("{:?}", s),
+		Yaml::Null => "null".to_string(),
+		Yaml::Array(_) => "a sequence".to_string(),
+		Yaml::Hash(_) => "a mapping".to_string(),
+		_ => ::yaml::string(yaml, true).unwrap_or_default(),
+	}
+}
+
+fn number(yaml: &Yaml) -> Option<f64> {
+	match yaml {
+		Yaml::Integer(x) => Some(*x as f64),
+		Yaml::Real(s) => s.parse::<f64>().ok(),
+		_ => None,
+	}
+}
+
+fn equal(a: &Yaml, b: &Yaml) -> bool { // JSON equality, where 1 and 1.0 are the same and key order doesn't matter
+	match (a, b) {
+		(Yaml::Array(x), Yaml::Array(y)) => x.len() == y.len() && x.iter().zip(y.iter()).all(|(x, y)| equal(x, y)),
+		(Yaml::Hash(x), Yaml::Hash(y)) => x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).is_some_and(|w| equal(v, w))),
+		_ => match (number(a), number(b)) {
+			(Some(x), Some(y)) => x == y,
+			_ => a == b,
+		},
+	}
+}
+
+fn has_type(name: &str, yaml: &Yaml) -> Result<bool> {
+	Ok(match (name, yaml) {
+		("null", Yaml::Null) | ("boolean", Yaml::Boolean(_)) | ("string", Yaml::String(_)) => true,
+		("object", Yaml::Hash(_)) | ("array", Yaml::Array(_)) => true,
+		("number", _) => number(yaml).is_some(),
+		("integer", _) => number(yaml).is_some_and(|x| x.fract() == 0.0),
+		("null", _) | ("boolean", _) | ("string", _) | ("object", _) | ("array", _) => false,
+		_ => bail!(format!("Unknown type {}", name)),
+	})
+}
+
+fn multiple(x: f64, of: f64) -> bool { // Allowing for rounding, since 0.3 / 0.1 is 2.9999999999999996
+	let quotient = x / of;
+	(quotient - quotient.round()).abs() <= 1e-9 * quotient.abs().max(1.0)
+}
+
+fn anchor<'a>(schema: &'a Yaml, name: &str) -> Option<&'a Yaml> { // The subschema with a matching `$anchor`
+	match schema {
+		Yaml::Hash(h) if h.get(&Yaml::String("$anchor".to_string())).and_then(|a| a.as_str()) == Some(name) => Some(schema),
+		Yaml::Hash(h) => h.values().find_map(|s| anchor(s, name)),
+		Yaml::Array(a) => a.iter().find_map(|s| anchor(s, name)),
+		_ => None,
+	}
+}
+
+fn count(yaml: &Yaml, keyword: &str) -> Result<usize> {
+	match yaml {
+		Yaml::Integer(x) if *x >= 0 => Ok(*x as usize),
+		_ => bail!(format!("{} must be a non-negative integer", keyword)),
+	}
+}
+
+pub struct Validator {
+	root: Yaml,
+	resolving: RefCell<Vec<(String, Vec<String>)>>, // References being followed and the paths they were followed at, to catch loops
+}
+
+impl Validator {
+	pub fn new(schema: Yaml) -> Self {
+		Validator { root: schema, resolving: RefCell::new(vec![]) }
+	}
+	fn resolve(&self, reference: &str) -> Result<&Yaml> { // Follows a JSON pointer such as `#/$defs/host`, or an anchor such as `#host`
+		let pointer = match reference.strip_prefix('#') {
+			Some(p) => p,
+			None => bail!(format!("Only references within the schema are supported, not {}", reference)),
+		};
+		if ! pointer.is_empty() && ! pointer.starts_with('/') {
+			return anchor(&self.root, pointer).ok_or_else(|| Error::from(format!("Reference {} doesn't exist", reference)));
+		}
+		let mut cur = &self.root;
+		for token in pointer.split('/').skip(1) {
+			let token = token.replace("~1", "/").replace("~0", "~");
+			cur = match cur {
+				Yaml::Hash(h) => h.get(&Yaml::String(token.to_string())),
+				Yaml::Array(a) => token.parse::<usize>().ok().and_then(|i| a.get(i)),
+				_ => None,
+			}.ok_or_else(|| Error::from(format!("Reference {} doesn't exist", reference)))?;
+		}
+		Ok(cur)
+	}
+	fn passes(&self, schema: &Yaml, value: &Yaml, path: &[String]) -> Result<bool> {
+		let mut errors = vec![];
+		self.check(schema, value, path, &mut errors)?;
+		Ok(errors.is_empty())
+	}
+	fn check(&self, schema: &Yaml, value: &Yaml, path: &[String], errors: &mut Vec<String>) -> Result<()> {
+		let keywords = match schema {
+			Yaml::Boolean(true) => return Ok(()),
+			Yaml::Boolean(false) => { errors.push(format!("{}: is not allowed", show_path(path))); return Ok(()); },
+			Yaml::Hash(h) => h,
+			_ => bail!("Schemas must be mappings or booleans"),
+		};
+		let child = |key: &str| { let mut ret = path.to_vec(); ret.push(key.to_string()); ret };
+		let mut fail = |at: &[String], message: String| errors.push(format!("{}: {}", show_path(at), message));
+		let mut nested = vec![];
+		let mut references = vec![]; // Checked apart from nested, since each is only being followed while its target is checked
+		let get = |name: &str| keywords.get(&Yaml::String(name.to_string()));
+		for (keyword, arg) in keywords.iter() {
+			let keyword = keyword.as_str().unwrap_or("");
+			if UNSUPPORTED.contains(&keyword) { bail!(format!("The {} keyword isn't supported", keyword)); }
+			match (keyword, value) {
+				("$ref", _) => {
+					let reference = arg.as_str().ok_or("$ref must be a string")?;
+					let following = (reference.to_string(), path.to_vec());
+					if self.resolving.borrow().contains(&following) { bail!(format!("Reference {} leads back to itself at {}", reference, show_path(path))); }
+					references.push((self.resolve(reference)?, following));
+				},
+				("type", _) => {
+					let names = match arg {
+						Yaml::Array(a) => a.iter().map(|t| t.as_str().ok_or("type must be a string or sequence of strings")).collect::<::std::result::Result<Vec<&str>, &str>>()?,
+						_ => vec![arg.as_str().ok_or("type must be a string or sequence of strings")?],
+					};
+					if ! names.iter().map(|t| has_type(t, value)).collect::<Result<Vec<bool>>>()?.contains(&true) {
+						fail(path, format!("must be of type {}, but is {}", names.join(" or "), show_value(value)));
+					}
+				},
+				("enum", _) => {
+					let allowed = arg.as_vec().ok_or("enum must be a sequence")?;
+					if ! allowed.iter().any(|a| equal(a, value)) {
+						fail(path, format!("must be one of {}", allowed.iter().map(show_value).collect::<Vec<String>>().join(", ")));
+					}
+				},
+				("const", _) if ! equal(arg, value) => match arg {
+					Yaml::Array(_) | Yaml::Hash(_) => fail(path, "doesn't match the required value".to_string()),
+					_ => fail(path, format!("must be {}", show_value(arg))),
+				},
+				("allOf", _) | ("anyOf", _) | ("oneOf", _) => {
+					let schemas = arg.as_vec().ok_or_else(|| format!("{} must be a sequence", keyword))?;
+					if keyword == "allOf" { for s in schemas { nested.push((s, path.to_vec(), value)); } continue; }
+					let passed = schemas.iter().map(|s| self.passes(s, value, path)).collect::<Result<Vec<bool>>>()?.into_iter().filter(|p| *p).count();
+					if keyword == "anyOf" && passed == 0 { fail(path, "doesn't match any of the allowed schemas".to_string()); }
+					if keyword == "oneOf" && passed != 1 { fail(path, format!("must match exactly one schema, but matches {}", passed)); }
+				},
+				("not", _) if self.passes(arg, value, path)? => fail(path, "matches a schema it must not match".to_string()),
+				("if", _) => {
+					let branch = if self.passes(arg, value, path)? { get("then") } else { get("else") };
+					if let Some(s) = branch { nested.push((s, path.to_vec(), value)); }
+				},
+				// Numbers
+				("multipleOf", _) | ("maximum", _) | ("exclusiveMaximum", _) | ("minimum", _) | ("exclusiveMinimum", _) => {
+					let (limit, x) = match (number(arg), number(value)) {
+						(Some(limit), Some(x)) => (limit, x),
+						(None, _) => bail!(format!("{} must be a number", keyword)),
+						(_, None) => continue,
+					};
+					match keyword {
+						"multipleOf" if ! multiple(x, limit) => fail(path, format!("must be a multiple of {}", limit)),
+						"maximum" if x > limit => fail(path, format!("must be at most {}", limit)),
+						"exclusiveMaximum" if x >= limit => fail(path, format!("must be less than {}", limit)),
+						"minimum" if x < limit => fail(path, format!("must be at least {}", limit)),
+						"exclusiveMinimum" if x <= limit => fail(path, format!("must be greater than {}", limit)),
+						_ => (),
+					}
+				},
+				// Strings
+				("maxLength", Yaml::String(s)) if s.chars().count() > count(arg, keyword)? => fail(path, format!("must be at most {} characters long", count(arg, keyword)?)),
+				("minLength", Yaml::String(s)) if s.chars().count() < count(arg, keyword)? => fail(path, format!("must be at least {} characters long", count(arg, keyword)?)),
+				("pattern", Yaml::String(s)) => {
+					let pattern = arg.as_str().ok_or("pattern must be a string")?;
+					if ! Regex::new(pattern).chain_err(|| format!("Invalid pattern {}", pattern))?.is_match(s) { fail(path, format!("must match {}", pattern)); }
+				},
+				// Arrays
+				("maxItems", Yaml::Array(a)) if a.len() > count(arg, keyword)? => fail(path, format!("must have at most {} items", count(arg, keyword)?)),
+				("minItems", Yaml::Array(a)) if a.len() < count(arg, keyword)? => fail(path, format!("must have at least {} items", count(arg, keyword)?)),
+				("uniqueItems", Yaml::Array(a)) if *arg == Yaml::Boolean(true) && a.iter().enumerate().any(|(i, x)| a[..i].iter().any(|y| equal(x, y))) => {
+					fail(path, "must not contain duplicate items".to_string());
+				},
+				("prefixItems", Yaml::Array(a)) => {
+					for (i, (s, v)) in arg.as_vec().ok_or("prefixItems must be a sequence")?.iter().zip(a.iter()).enumerate() { nested.push((s, child(&i.to_string()), v)); }
+				},
+				("items", Yaml::Array(a)) => {
+					let skip = get("prefixItems").and_then(|p| p.as_vec()).map_or(0, |p| p.len());
+					for (i, v) in a.iter().enumerate().skip(skip) { nested.push((arg, child(&i.to_string()), v)); }
+				},
+				("contains", Yaml::Array(a)) => {
+					let found = a.iter().enumerate().map(|(i, v)| self.passes(arg, v, &child(&i.to_string()))).collect::<Result<Vec<bool>>>()?.into_iter().filter(|p| *p).count();
+					let min = get("minContains").map_or(Ok(1), |m| count(m, "minContains"))?;
+					let max = get("maxContains").map(|m| count(m, "maxContains")).transpose()?;
+					if found < min { fail(path, format!("must contain at least {} matching items, but contains {}", min, found)); }
+					if max.is_some_and(|max| found > max) { fail(path, format!("must contain at most {} matching items, but contains {}", max.unwrap_or(0), found)); }
+				},
+				// Objects
+				("maxProperties", Yaml::Hash(h)) if h.len() > count(arg, keyword)? => fail(path, format!("must have at most {} keys", count(arg, keyword)?)),
+				("minProperties", Yaml::Hash(h)) if h.len() < count(arg, keyword)? => fail(path, format!("must have at least {} keys", count(arg, keyword)?)),
+				("required", Yaml::Hash(h)) => for key in arg.as_vec().ok_or("required must be a sequence")? {
+					if ! h.contains_key(key) { fail(&child(key.as_str().unwrap_or("")), "is required".to_string()); }
+				},
+				("dependentRequired", Yaml::Hash(h)) => for (key, needed) in arg.as_hash().ok_or("dependentRequired must be a mapping")? {
+					if ! h.contains_key(key) { continue; }
+					for n in needed.as_vec().ok_or("dependentRequired values must be sequences")? {
+						if ! h.contains_key(n) { fail(&child(n.as_str().unwrap_or("")), format!("is required when {} is present", key.as_str().unwrap_or(""))); }
+					}
+				},
+				("dependentSchemas", Yaml::Hash(h)) => for (key, s) in arg.as_hash().ok_or("dependentSchemas must be a mapping")? {
+					if h.contains_key(key) { nested.push((s, path.to_vec(), value)); }
+				},
+				("properties", Yaml::Hash(h)) => for (key, s) in arg.as_hash().ok_or("properties must be a mapping")? {
+					if let Some(v) = h.get(key) { nested.push((s, child(key.as_str().unwrap_or("")), v)); }
+				},
+				("patternProperties", Yaml::Hash(h)) => for (pattern, s) in arg.as_hash().ok_or("patternProperties must be a mapping")? {
+					let pattern = pattern.as_str().ok_or("patternProperties keys must be strings")?;
+					let re = Regex::new(pattern).chain_err(|| format!("Invalid pattern {}", pattern))?;
+					for (k, v) in h.iter().filter(|(k, _)| k.as_str().is_some_and(|k| re.is_match(k))) { nested.push((s, child(k.as_str().unwrap_or("")), v)); }
+				},
+				("additionalProperties", Yaml::Hash(h)) => {
+					let named = get("properties").and_then(|p| p.as_hash());
+					let patterns = get("patternProperties").and_then(|p| p.as_hash()).map_or(Ok(vec![]), |p| p.keys().map(|k| Regex::new(k.as_str().unwrap_or("")).chain_err(|| "Invalid pattern")).collect::<Result<Vec<Regex>>>())?;
+					for (k, v) in h {
+						let key = k.as_str().unwrap_or("");
+						if named.is_some_and(|n| n.contains_key(k)) || patterns.iter().any(|re| re.is_match(key)) { continue; }
+						if *arg == Yaml::Boolean(false) { fail(&child(key), "is not allowed".to_string()); }
+						else { nested.push((arg, child(key), v)); }
+					}
+				},
+				("propertyNames", Yaml::Hash(h)) => for k in h.keys() {
+					if ! self.passes(arg, k, path)? { fail(path, format!("has a key {} with a name that isn't allowed", show_value(k))); }
+				},
+				_ => (), // Annotations, and keywords that don't apply to this type of value
+			}
+		}
+		for (target, following) in references { // Only while checking the target, so that other keywords here can use the same reference
+			self.resolving.borrow_mut().push(following);
+			let checked = self.check(target, value, path, errors);
+			self.resolving.borrow_mut().pop();
+			checked?;
+		}
+		for (s, p, v) in nested { self.check(s, v, &p, errors)?; }
+		Ok(())
+	}
+	pub fn validate(&self, values: &Yaml) -> Result<()> { // Reports every value that doesn't match the schema
+		let mut errors = vec![];
+		self.check(&self.root, values, &[], &mut errors).chain_err(|| "Invalid JSON Schema")?;
+		if ! errors.is_empty() { bail!(format!("Values don't match the JSON Schema:\n  {}", errors.join("\n  "))); }
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::yaml_rust::YamlLoader;
+	fn load(s: &str) -> Yaml {
+		YamlLoader::load_from_str(s).unwrap().remove(0)
+	}
+	fn errors(schema: &str, values: &str) -> Vec<String> {
+		match Validator::new(load(schema)).validate(&load(values)) {
+			Ok(()) => vec![],
+			Err(e) => e.to_string().lines().skip(1).map(|l| l.trim().to_string()).collect(),
+		}
+	}
+	#[test]
+	fn jsonschema_objects() {
+		let schema = r##"{
+			"$schema": "https://json-schema.org/draft/2020-12/schema",
+			"type": "object",
+			"required": ["replicas", "name"],
+			"properties": {
+				"replicas": {"type": "integer", "minimum": 1},
+				"name": {"type": "string", "pattern": "^[a-z]+$"},
+				"hosts": {"type": "array", "items": {"$ref": "#/$defs/host"}, "uniqueItems": true}
+			},
+			"additionalProperties": false,
+			"$defs": {"host": {"type": "object", "required": ["port"], "properties": {"port": {"type": "integer", "maximum": 65535}}}}
+		}"##;
+		assert!(errors(schema, "replicas: 2\nname: web\nhosts: [{port: 80}, {port: 443}]").is_empty());
+		assert_eq!(errors(schema, "replica: 2\nname: Web\nhosts: [{port: 80}, {}, {port: 99999}]"), vec![
+			"replicas: is required",
+			"replica: is not allowed",
+			"name: must match ^[a-z]+$",
+			"hosts.1.port: is required",
+			"hosts.2.port: must be at most 65535",
+		]);
+		assert_eq!(errors(schema, "[]"), vec![".: must be of type object, but is a sequence"]);
+	}
+	#[test]
+	fn jsonschema_keywords() {
+		assert!(errors(r#"{"enum": [1, "a", null]}"#, "1.0").is_empty());
+		assert_eq!(errors(r#"{"enum": [1, "a"]}"#, "b"), vec![r#".: must be one of 1, "a""#]);
+		assert_eq!(errors(r#"{"const": {"a": [1]}}"#, "{a: [2]}"), vec![".: doesn't match the required value"]);
+		assert!(errors(r#"{"type": ["integer", "null"]}"#, "~").is_empty());
+		assert!(errors(r#"{"anyOf": [{"type": "string"}, {"minimum": 3}]}"#, "4").is_empty());
+		assert_eq!(errors(r#"{"oneOf": [{"type": "integer"}, {"minimum": 3}]}"#, "4"), vec![".: must match exactly one schema, but matches 2"]);
+		assert_eq!(errors(r#"{"not": {"type": "string"}}"#, "a"), vec![".: matches a schema it must not match"]);
+		assert_eq!(errors(r#"{"if": {"properties": {"tls": {"const": true}}}, "then": {"required": ["cert"]}}"#, "tls: true"), vec!["cert: is required"]);
+		assert!(errors(r#"{"if": {"properties": {"tls": {"const": true}}}, "then": {"required": ["cert"]}}"#, "tls: false").is_empty());
+		assert_eq!(errors(r#"{"prefixItems": [{"type": "string"}], "items": false}"#, "[a, 1]"), vec!["1: is not allowed"]);
+		assert_eq!(errors(r#"{"contains": {"type": "integer"}, "maxContains": 1}"#, "[1, a, 2]"), vec![".: must contain at most 1 matching items, but contains 2"]);
+		assert_eq!(errors(r#"{"dependentRequired": {"a": ["b"]}}"#, "a: 1"), vec!["b: is required when a is present"]);
+		assert_eq!(errors(r#"{"propertyNames": {"maxLength": 2}, "minProperties": 3}"#, "abc: 1"), vec![
+			r#".: has a key "abc" with a name that isn't allowed"#,
+			".: must have at least 3 keys",
+		]);
+		assert_eq!(errors(r#"{"multipleOf": 0.5, "exclusiveMaximum": 2}"#, "2.25"), vec![".: must be a multiple of 0.5", ".: must be less than 2"]);
+		assert_eq!(errors(r#"{"patternProperties": {"^x-": {"type": "string"}}, "additionalProperties": {"type": "integer"}}"#, "x-a: 1\nb: c"), vec![
+			"x-a: must be of type string, but is 1",
+			"b: must be of type integer, but is \"c\"",
+		]);
+		assert!(Validator::new(load(r#"{"unevaluatedProperties": false}"#)).validate(&load("a: 1")).is_err());
+		assert!(Validator::new(load(r#"{"$ref": "other.json"}"#)).validate(&load("a: 1")).is_err());
+		assert!(Validator::new(load(r#"{"type": "text"}"#)).validate(&load("a: 1")).is_err());
+		assert_eq!(errors(r#"{"multipleOf": 0.1}"#, "0.3"), Vec::<String>::new());
+		assert_eq!(errors(r#"{"multipleOf": 0.01}"#, "19.99"), Vec::<String>::new());
+		assert_eq!(errors(r#"{"multipleOf": 0.1}"#, "0.35"), vec![".: must be a multiple of 0.1"]);
+		let looping = Validator::new(load(r##"{"$ref": "#"}"##)).validate(&load("a: 1")).unwrap_err();
+		assert!(looping.iter().any(|e| e.to_string() == "Reference # leads back to itself at ."));
+		assert!(Validator::new(load(r##"{"$defs": {"a": {"$ref": "#/$defs/b"}, "b": {"$ref": "#/$defs/a"}}, "$ref": "#/$defs/a"}"##)).validate(&load("a: 1")).is_err());
+		let anchored = r##"{"$defs": {"n": {"$anchor": "num", "type": "integer"}}, "properties": {"a": {"$ref": "#num"}, "b": {"$ref": "#nope"}}}"##;
+		assert_eq!(errors(anchored, "a: x"), vec!["a: must be of type integer, but is \"x\""]);
+		assert_eq!(errors(anchored, "a: 5"), Vec::<String>::new());
+		assert!(Validator::new(load(anchored)).validate(&load("b: 1")).is_err());
+		let twice = r##"{"$defs": {"pos": {"minimum": 0}}, "properties": {"a": {"$ref": "#/$defs/pos", "anyOf": [{"$ref": "#/$defs/pos"}]}}}"##; // The same reference in two places isn't a loop
+		assert_eq!(errors(twice, "a: 1"), Vec::<String>::new());
+		assert_eq!(errors(twice, "a: -1"), vec!["a: doesn't match any of the allowed schemas", "a: must be at least 0"]);
+		let tree = r##"{"type": "object", "properties": {"v": {"type": "integer"}, "c": {"type": "array", "items": {"$ref": "#"}}}}"##; // Recursion into the value is fine
+		assert_eq!(errors(tree, "v: 1\nc: [{v: 2, c: [{v: x}]}]"), vec!["c.0.c.0.v: must be of type integer, but is \"x\""]);
+	}
+}
@@ -0,0 +1,165 @@
+use ::std::collections::BTreeMap;
+use ::parse::*;
+use ::expr::Expr;
+use ::vars::{expr_paths, ITEM};
+
+// Finds likely mistakes in a template without any values.  It works on the tokens rather than the tree, since building the tree
+// closes sections at the end of the input and drops everything after an end tag that doesn't match a section.  Contexts are
+// followed the same way as when rendering, with `*` standing for the items of a section, so that paths going above the root and
+// `{{?n}}` tags with too few keys to reach can be found.
+
+#[derive(Clone)]
+struct Frame {
+	pos: Pos,
+	context: Option<YamlPath>, // Unknown inside macros, which can be called from anywhere
+	aliases: BTreeMap<String, YamlPath>,
+	inverted: bool, // Whether the innermost section over a path is inverted, so its context is the value it tested rather than an item
+}
+
+#[derive(Default)]
+struct Linter {
+	problems: Vec<(Pos, String)>,
+}
+
+impl Linter {
+	fn resolve(&mut self, frame: &Frame, path: &YamlPath, pos: Pos) -> Option<YamlPath> { // None if the result isn't known
+		let (mut ret, rest) = match path.first() {
+			Some(YamlPathElem::Down(ref name)) if frame.aliases.contains_key(name) => (frame.aliases[name].to_vec(), &path[1..]),
+			Some(YamlPathElem::Root) => (vec![], &path[..]),
+			_ => (frame.context.clone()?, &path[..]),
+		};
+		for elem in rest {
+			match elem {
+				YamlPathElem::Root => ret.clear(),
+				YamlPathElem::Up if ret.pop().is_none() => {
+					self.problems.push((pos, format!("{} goes above the root of the values", ::print::path(path))));
+					return None;
+				},
+				YamlPathElem::Up => (),
+				YamlPathElem::Down(_) => ret.push(elem.clone()),
+			}
+		}
+		Some(ret)
+	}
+	fn exprs(&mut self, frame: &Frame, expr: &Expr, pos: Pos) {
+		let mut paths = vec![];
+		expr_paths(expr, &mut paths);
+		for path in paths { self.resolve(frame, path, pos); }
+	}
+	fn section(&mut self, frame: &Frame, target: &Expr, name: Option<&String>, mods: &Modifiers, direct: bool, pos: Pos) -> Frame {
+		let mut inner = Frame { pos, ..frame.clone() };
+		let path = match target {
+			Expr::Path(ref path) => path,
+			expr => { // Computed values have no path, so the context doesn't change
+				self.exprs(frame, expr, pos);
+				return inner;
+			},
+		};
+		let abspath = self.resolve(frame, path, pos);
+		let item = abspath.as_ref().map(|p| ::yaml::pathjoin(&[p, &vec![YamlPathElem::Down(ITEM.to_string())]]));
+		if let (Some(name), Some(item)) = (name, item.as_ref()) { inner.aliases.insert(name.to_string(), item.to_vec()); }
+		let item_frame = Frame { context: item.clone(), ..inner.clone() };
+		if let Some(ref filter) = mods.filter { self.exprs(&item_frame, filter, pos); }
+		if let Some((ref key, _)) = mods.sort { self.resolve(&item_frame, key, pos); }
+		inner.context = if direct { item } else { abspath };
+		inner.inverted = ! direct;
+		inner
+	}
+}
+
+pub fn lint(tokens: &[(Token, Source)], open: &str, close: &str) -> Vec<(Pos, String)> { // Problems in the order they appear
+	let mut linter = Linter::default();
+	let mut stack = vec![Frame { pos: Pos::default(), context: Some(vec![]), aliases: BTreeMap::new(), inverted: false }];
+	let (mut open, mut close) = (open.to_string(), close.to_string());
+	for (i, (token, source)) in tokens.iter().enumerate() {
+		let pos = source.pos;
+		let frame = stack.last().cloned().unwrap_or_else(|| unreachable!()); // The root is never popped
+		match token {
+			Token::Literal(..) => (),
+			Token::DirectSub(ref expr, _) => linter.exprs(&frame, expr, pos),
+			Token::CondSub(ref target, ref name, ref mods) => stack.push(linter.section(&frame, target, name.as_ref(), mods, true, pos)),
+			Token::InvSub(ref target, ref mods) => stack.push(linter.section(&frame, target, None, mods, false, pos)),
+			Token::With(ref path, ref name) => {
+				let mut inner = Frame { pos, ..frame.clone() };
+				match linter.resolve(&frame, path, pos) {
+					Some(bound) => { inner.aliases.insert(name.to_string(), bound); },
+					None => { inner.aliases.remove(name); },
+				}
+				stack.push(inner);
+			},
+			Token::Let(ref name, ref path) => {
+				let bound = linter.resolve(&frame, path, pos);
+				if let Some(top) = stack.last_mut() {
+					match bound {
+						Some(bound) => { top.aliases.insert(name.to_string(), bound); },
+						None => { top.aliases.remove(name); },
+					}
+				}
+			},
+			Token::Define(_) => stack.push(Frame { pos, context: None, aliases: BTreeMap::new(), inverted: false }),
+			Token::Call(_, ref context, ref params) => {
+				if let Some(ref context) = context { linter.resolve(&frame, context, pos); }
+				for (_, path) in params { linter.resolve(&frame, path, pos); }
+			},
+			Token::EndSub if stack.len() == 1 => linter.problems.push((pos, "This end tag doesn't close any section, so the rest of the template is ignored".to_string())),
+			Token::EndSub => { stack.pop(); },
+			Token::KeySub(n) => match frame.context {
+				Some(ref context) if *n as usize >= context.len() => linter.problems.push((pos, format!("{} is deeper than the sections around it", source.text))),
+				Some(_) if *n == 0 && frame.inverted => linter.problems.push((pos, format!("{} in an inverted section is the key of the value it tested, not of an item", source.text))),
+				_ => (),
+			},
+			Token::Comment(_, long) => {
+				let ends_early = match tokens.get(i + 1) {
+					Some((Token::Literal(ref s, _), _)) => s.contains(&close[..]),
+					_ => false,
+				};
+				if ends_early && ! long {
+					linter.problems.push((pos, format!("This comment ends at the first {}, so the rest is output; use {}!-- --{} instead", close, open, close)));
+				}
+			},
+			Token::SetDelim(ref o, ref c) => {
+				open = o.to_string();
+				close = c.to_string();
+			},
+		}
+	}
+	for frame in &stack[1..] { linter.problems.push((frame.pos, "This section is never closed, so it ends at the end of the template".to_string())); }
+	linter.problems.sort_by_key(|p| p.0);
+	linter.problems
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn lint(tpl: &str) -> Vec<String> {
+		super::lint(&Parser::new(tpl).get_tokens("{{", "}}").unwrap(), "{{", "}}").into_iter().map(|(pos, message)| format!("{}: {}", pos, message)).collect()
+	}
+	#[test]
+	fn lint_clean() {
+		assert_eq!(lint("{{#a as x where b > 1}}{{?}}{{&.c}}{{x.d}}{{#e}}{{?1}}{{/}}{{/}}{{!-- }} --}}{{*define m}}{{&.&.z}}{{?3}}{{/}}"), Vec::<String>::new());
+	}
+	#[test]
+	fn lint_problems() {
+		assert_eq!(lint("{{/}}{{#a}}{{#b}}"), vec![
+			"1:1: This end tag doesn't close any section, so the rest of the template is ignored",
+			"1:6: This section is never closed, so it ends at the end of the template",
+			"1:12: This section is never closed, so it ends at the end of the template",
+		]);
+		assert_eq!(lint("{{&.a}}{{#b}}{{&.&.&.c}}{{#with &.d as e}}{{e.&.&.&.f}}{{/}}{{/}}{{let g = .h}}{{g.&.&.i}}"), vec![
+			"1:1: &.a goes above the root of the values",
+			"1:14: &.&.&.c goes above the root of the values",
+			"1:43: e.&.&.&.f goes above the root of the values",
+			"1:80: g.&.&.i goes above the root of the values",
+		]);
+		assert_eq!(lint("{{?}}{{#a.b}}{{?2}}{{?3}}{{/}}{{^c}}{{?}}{{#d > 1}}{{?}}{{/}}{{/}}"), vec![
+			"1:1: {{?}} is deeper than the sections around it",
+			"1:20: {{?3}} is deeper than the sections around it",
+			"1:37: {{?}} in an inverted section is the key of the value it tested, not of an item",
+			"1:52: {{?}} in an inverted section is the key of the value it tested, not of an item",
+		]);
+		assert_eq!(lint("{{! a }} b }}{{=<% %>=}}<%! c %> %>"), vec![
+			"1:1: This comment ends at the first }}, so the rest is output; use {{!-- --}} instead",
+			"1:25: This comment ends at the first %>, so the rest is output; use <%!-- --%> instead",
+		]);
+	}
+}
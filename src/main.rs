@@ -2,17 +2,45 @@
 #[macro_use] extern crate clap;
 #[macro_use] extern crate nom;
 extern crate yaml_rust;
+extern crate regex;
+extern crate notify;
+extern crate similar;
 
 mod parse;
 mod yaml;
+mod mustache;
+mod format;
+mod expr;
+mod schema;
+mod jsonschema;
+mod vars;
+mod report;
+mod print;
+mod dump;
+mod trace;
+mod lint;
+mod build;
 
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::Duration;
 use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
 use yaml_rust::Yaml;
+use yaml_rust::yaml::Hash;
+use clap::{App, AppSettings, Arg, ArgMatches, Shell};
+use notify::{Watcher, RecursiveMode, DebouncedEvent};
+use error_chain::ChainedError;
+use similar::TextDiff;
 use parse::*;
+use vars::Usage;
+use report::Report;
+use trace::Trace;
 
+#[allow(unexpected_cfgs)]
 mod errors { error_chain!{} }
 use errors::*;
 
@@ -30,31 +58,268 @@ fn read_file(path: &str) -> Result<String> {
 	Ok(ret)
 }
 
-fn render(values: &Yaml, tree: &[Node], context: &YamlPath, ignore: bool) -> Result<String> {
+#[derive(Clone, Debug, Default)]
+struct Scope<'a> {
+	aliases: BTreeMap<String, YamlPath>, // Names bound with `as` or `let`, mapped to absolute paths
+	macros: BTreeMap<String, &'a [Node]>,
+	iteration: Option<expr::Loop>, // The innermost section item being rendered
+	report: Option<&'a RefCell<Report>>, // Where to record lookups, with --report
+	partial: Option<(&'a str, &'a str)>, // The delimiters to output unresolved tags with, with --partial
+	trace: Option<&'a RefCell<Trace>>, // Where to record rendering steps, with --trace
+	calls: Vec<String>, // Macros being rendered, innermost last
+}
+
+const MAX_CALLS: usize = 100; // Deeper than any template needs, but shallow enough to fail before the stack overflows
+
+fn partial_path(path: &YamlPath, context: &YamlPath) -> YamlPath { // Writes a resolved path so that it means the same thing where an unresolved tag is output
+	let item = YamlPathElem::Down(vars::ITEM.to_string()); // Stands for the items of an unresolved section, which can only be reached relatively
+	if ! path.contains(&item) { return std::iter::once(YamlPathElem::Root).chain(path.iter().cloned()).collect(); }
+	let common = path.iter().zip(context.iter()).take_while(|(a, b)| a == b).count();
+	std::iter::repeat_n(YamlPathElem::Up, context.len() - common).chain(path[common..].iter().cloned()).collect()
+}
+
+impl<'a> Scope<'a> {
+	fn bind(&self, name: &str, path: YamlPath) -> Self {
+		let mut ret = self.clone();
+		ret.aliases.insert(name.to_string(), path);
+		ret
+	}
+	fn resolve(&self, context: &YamlPath, path: &YamlPath) -> YamlPath {
+		if let Some(YamlPathElem::Down(ref name)) = path.first() {
+			if let Some(bound) = self.aliases.get(name) { return yaml::pathjoin(&[bound, &path[1..].to_vec()]); }
+		}
+		yaml::pathjoin(&[context, path])
+	}
+	fn text(&self, s: &str) -> String { // Rendered text, which mustn't be read as tags when rendering partially
+		match self.partial {
+			Some((open, close)) => print::literal(s, open, close),
+			None => s.to_string(),
+		}
+	}
+	fn unresolved(&self, values: &Yaml, context: &YamlPath, expr: &expr::Expr) -> expr::Expr { // Writes in the values that are known
+		let (open, close) = self.partial.unwrap_or(("{{", "}}"));
+		expr::substitute(expr, &|e| {
+			let value = expr::eval(values, e, &|path| self.resolve(context, path), self.iteration).unwrap_or(Yaml::BadValue);
+			match (expr::literal(&value), e) {
+				(Some(expr::Expr::Str(ref s)), _) if s.contains(open) || s.contains(close) => None,
+				(Some(literal), _) => Some(literal),
+				(None, expr::Expr::Path(ref path)) => Some(expr::Expr::Path(partial_path(&self.resolve(context, path), context))),
+				(None, _) => None,
+			}
+		})
+	}
+	fn record(&self, values: &Yaml, path: &YamlPath, pos: Pos, usage: Usage) {
+		if let Some(report) = self.report { report.borrow_mut().record(path, pos, usage, ! yaml::get(values, path).is_badvalue()); }
+	}
+	fn lookup(&self, values: &Yaml, context: &YamlPath, path: &YamlPath, pos: Pos, usage: Usage) -> YamlPath { // Resolves a path and records its use
+		let ret = self.follow(values, context, path, pos);
+		self.record(values, &ret, pos, usage);
+		ret
+	}
+	fn follow(&self, values: &Yaml, context: &YamlPath, path: &YamlPath, pos: Pos) -> YamlPath { // Resolves a path that's only bound to a name, so it's traced but isn't a use
+		let ret = self.resolve(context, path);
+		if let Some(trace) = self.trace { trace.borrow_mut().lookup(pos, path, &ret, yaml::get(values, &ret)); }
+		ret
+	}
+	fn step(&self, source: &Source, outcome: &str) {
+		if let Some(trace) = self.trace { trace.borrow_mut().section(source.pos, &source.text, outcome); }
+	}
+}
+
+// Lists the paths a section iterates over, after filtering, sorting, and limiting them
+fn section_items(values: &Yaml, path: &YamlPath, name: &Option<String>, mods: &Modifiers, scope: &Scope, pos: Pos, ignore: Ignore) -> Result<Vec<YamlPath>> {
+	let target = yaml::get(values, path);
+	let child_path = |elem| yaml::pathjoin(&[path, &vec![elem]]);
+	let mut items = match target {
+		Yaml::Hash(ref contents) => contents.keys().map(|k| match k {
+			Yaml::String(ref s) => Ok(child_path(YamlPathElem::Down(s.to_string()))),
+			_ => Err(Error::from("All YAML keys must be strings")),
+		}).collect::<Result<Vec<YamlPath>>>()?,
+		Yaml::Array(ref contents) => (0..contents.len()).map(|i| child_path(YamlPathElem::Down(i.to_string()))).collect(),
+		_ if yaml::bool(target) => vec![path.to_vec()],
+		_ => vec![],
+	};
+	let item_scope = |item: &YamlPath| match name {
+		Some(ref name) => scope.bind(name, item.to_vec()),
+		None => scope.clone(),
+	};
+	if let Some(ref filter) = mods.filter {
+		let mut kept = vec![];
+		for item in items {
+			let inner = item_scope(&item);
+			match expr::eval(values, filter, &|p| inner.lookup(values, &item, p, pos, Usage::Tested), scope.iteration) {
+				Ok(ref value) if yaml::bool(value) => kept.push(item),
+				Ok(_) => (),
+				Err(_) if ignore != Ignore::Fail => (),
+				Err(e) => return Err(e).chain_err(|| "Couldn't evaluate where clause"),
+			}
+		}
+		items = kept;
+	}
+	if let Some((ref key, desc)) = mods.sort {
+		let mut keyed = items.into_iter().map(|item| (yaml::get(values, &item_scope(&item).lookup(values, &item, key, pos, Usage::Tested)), item)).collect::<Vec<(&Yaml, YamlPath)>>();
+		keyed.sort_by(|a, b| yaml::sort_order(a.0, b.0, desc));
+		items = keyed.into_iter().map(|(_, item)| item).collect();
+	}
+	if let Some(limit) = mods.limit { items.truncate(limit); }
+	Ok(items)
+}
+
+fn render<'a>(values: &Yaml, tree: &'a [Node], context: &YamlPath, scope: &Scope<'a>, ignore: Ignore) -> Result<String> {
 	let mut ret = "".to_string();
+	let mut scope = scope.clone(); // Modified by `let`, which lasts until the end of the current section
+	let mut defined = vec![];
+	for node in tree { // Macros can be called anywhere in the section they're defined in, including before the definition
+		if let Node::Define(ref name, ref body) = node {
+			if defined.contains(&name) { bail!(format!("Macro {} is defined more than once", name)); }
+			defined.push(name);
+			scope.macros.insert(name.to_string(), body);
+		}
+	}
 	for node in tree {
 		let cur = match node {
-			Node::Literal(ref s) => s.to_string(),
-			Node::DirectSub(ref path) => yaml::string(yaml::get(values, &yaml::pathjoin(&vec![context, path][..])), ignore).chain_err(|| "Couldn't stringify value")?,
-			Node::CondSub(ref path, direct, ref children) => {
-				let abspath = &yaml::pathjoin(&vec![context, path][..]);
-				let target = yaml::get(values, abspath);
-				if yaml::bool(target) && *direct {
-					let render_child = |child| render(values, children, &yaml::pathjoin(&vec![abspath, &vec![child]]), ignore);
-					match target {
-						Yaml::Hash(ref contents) => contents.keys().map(|k| match k {
-							Yaml::String(ref s) => render_child(YamlPathElem::Down(s.to_string())),
-							_ => Err(Error::from("All YAML keys must be strings")),
-						}).collect::<Result<String>>()?,
-						Yaml::Array(ref contents) => (0..contents.len() as i64).into_iter().map(|i| render_child(YamlPathElem::Down(i.to_string()))).collect::<Result<String>>()?,
-						_ => render(values, children, abspath, ignore)?,
-					}
+			Node::Literal(ref s, _) => scope.text(s),
+			Node::Comment(..) | Node::SetDelim(..) => String::new(),
+			Node::DirectSub(ref expr, ref format, ref source) => {
+				let missing = Cell::new(false); // Whether any value that was looked up doesn't exist
+				let resolve = |path: &YamlPath| {
+					let ret = scope.lookup(values, context, path, source.pos, Usage::Substituted);
+					if yaml::get(values, &ret).is_badvalue() { missing.set(true); }
+					ret
+				};
+				let target = match expr {
+					expr::Expr::Path(ref path) => Ok(yaml::get(values, &resolve(path)).clone()),
+					expr => expr::eval(values, expr, &resolve, scope.iteration).chain_err(|| "Couldn't evaluate expression"),
+				};
+				let output = target.and_then(|target| match format {
+					Some(ref format) => format.apply(&target).chain_err(|| "Couldn't format value"),
+					None => yaml::string(&target, false).chain_err(|| "Couldn't stringify value"),
+				});
+				let unresolved = missing.get() || (expr::uses_loop(expr) && scope.iteration.is_none()); // Loop variables are unknown inside unresolved sections
+				match output {
+					_ if unresolved && scope.partial.is_some() => {
+						let (open, close) = scope.partial.unwrap_or_default();
+						print::tag(&Node::DirectSub(scope.unresolved(values, context, expr), format.clone(), source.clone()), open, close)
+					},
+					_ if ignore == Ignore::Keep && missing.get() => source.text.to_string(), // Left for another tool to fill in
+					Ok(s) => scope.text(&s),
+					Err(_) if ignore == Ignore::Blank => "".to_string(),
+					Err(_) if ignore == Ignore::Keep => source.text.to_string(),
+					Err(e) => return Err(e),
 				}
-				else if ! yaml::bool(target) && ! *direct { render(values, children, abspath, ignore)? }
-				else { "".to_string() }
+			},
+			Node::CondSub(expr::Expr::Path(ref path), direct, ref name, ref mods, ref children, ref source) => {
+				let abspath = &scope.lookup(values, context, path, source.pos, if *direct { Usage::Iterated } else { Usage::Tested });
+				let items = section_items(values, abspath, name, mods, &scope, source.pos, ignore)?;
+				if let (Some((open, close)), true) = (scope.partial, yaml::get(values, abspath).is_badvalue()) { // Output with its contents rendered as far as they can be
+					let item = if *direct { yaml::pathjoin(&[abspath, &vec![YamlPathElem::Down(vars::ITEM.to_string())]]) } else { abspath.to_vec() };
+					let mut inner = match name {
+						Some(ref name) => scope.bind(name, item.clone()),
+						None => scope.clone(),
+					};
+					inner.iteration = None;
+					let mods = Modifiers {
+						filter: mods.filter.as_ref().map(|f| inner.unresolved(values, &item, f)),
+						sort: mods.sort.as_ref().map(|(key, desc)| (partial_path(&inner.resolve(&item, key), &item), *desc)),
+						limit: mods.limit,
+					};
+					let node = Node::CondSub(expr::Expr::Path(partial_path(abspath, context)), *direct, name.clone(), mods, vec![], source.clone());
+					scope.step(source, "output unresolved");
+					format!("{}{}{}", print::tag(&node, open, close), render(values, children, &item, &inner, ignore)?, print::end(open, close))
+				}
+				else if ignore == Ignore::Keep && yaml::get(values, abspath).is_badvalue() { // Left for another tool, with anything inside that can be resolved filled in
+					scope.step(source, "kept as written");
+					format!("{}{}{}", source.text, render(values, children, abspath, &scope, ignore)?, source.end)
+				}
+				else if *direct {
+					let count = items.len();
+					scope.step(source, &match yaml::get(values, abspath) {
+						_ if count == 0 => "skipped".to_string(),
+						Yaml::Array(_) | Yaml::Hash(_) => format!("iterated {} times", count),
+						_ => "taken".to_string(),
+					});
+					items.into_iter().enumerate().map(|(index, child)| {
+						let mut inner = match name {
+							Some(ref name) => scope.bind(name, child.clone()),
+							None => scope.clone(),
+						};
+						inner.iteration = Some(expr::Loop { index, count });
+						render(values, children, &child, &inner, ignore)
+					}).collect::<Result<String>>()?
+				}
+				else if items.is_empty() {
+					scope.step(source, "taken");
+					render(values, children, abspath, &scope, ignore)?
+				}
+				else {
+					scope.step(source, "skipped");
+					"".to_string()
+				}
+			},
+			Node::CondSub(ref expr, direct, ref name, ref mods, ref children, ref source) => { // Computed values have no path, so the context doesn't change
+				if name.is_some() || *mods != Modifiers::default() { bail!("Only sections over paths can bind names or use modifiers"); }
+				let missing = Cell::new(false);
+				let resolve = |path: &YamlPath| {
+					let ret = scope.lookup(values, context, path, source.pos, Usage::Tested);
+					if yaml::get(values, &ret).is_badvalue() { missing.set(true); }
+					ret
+				};
+				let value = match expr::eval(values, expr, &resolve, scope.iteration) {
+					Err(_) if ignore != Ignore::Fail => Ok(Yaml::BadValue),
+					value => value.chain_err(|| "Couldn't evaluate expression"),
+				};
+				let unresolved = missing.get() || (expr::uses_loop(expr) && scope.iteration.is_none());
+				match (scope.partial, value) {
+					(Some((open, close)), _) if unresolved => {
+						scope.step(source, "output unresolved");
+						let node = Node::CondSub(scope.unresolved(values, context, expr), *direct, None, Modifiers::default(), vec![], source.clone());
+						format!("{}{}{}", print::tag(&node, open, close), render(values, children, context, &scope, ignore)?, print::end(open, close))
+					},
+					(_, Err(e)) => return Err(e),
+					(_, Ok(ref value)) if ignore == Ignore::Keep && (missing.get() || value.is_badvalue()) => {
+						scope.step(source, "kept as written");
+						format!("{}{}{}", source.text, render(values, children, context, &scope, ignore)?, source.end)
+					},
+					(_, Ok(ref value)) if yaml::bool(value) == *direct => {
+						scope.step(source, "taken");
+						render(values, children, context, &scope, ignore)?
+					},
+					_ => {
+						scope.step(source, "skipped");
+						"".to_string()
+					},
+				}
+			},
+			Node::With(ref path, ref name, ref children, ref source) => render(values, children, context, &scope.bind(name, scope.follow(values, context, path, source.pos)), ignore)?,
+			Node::Let(ref name, ref path, ref source) => {
+				let bound = scope.follow(values, context, path, source.pos);
+				scope.aliases.insert(name.to_string(), bound);
+				"".to_string()
+			},
+			Node::Define(..) => "".to_string(),
+			Node::Call(ref name, ref path, ref params, ref source) => {
+				let body = scope.macros.get(name).ok_or_else(|| Error::from(format!("Call to undefined macro {}", name)))?;
+				if scope.calls.len() >= MAX_CALLS { bail!(format!("Macro {} is nested more than {} calls deep, so it probably calls itself forever", name, MAX_CALLS)); }
+				let inner = Scope {
+					aliases: params.iter().map(|(k, v)| (k.to_string(), scope.follow(values, context, v, source.pos))).collect(),
+					macros: scope.macros.clone(),
+					iteration: None,
+					report: scope.report,
+					partial: scope.partial,
+					trace: scope.trace,
+					calls: scope.calls.iter().cloned().chain(std::iter::once(name.to_string())).collect(),
+				};
+				let context = path.as_ref().map(|p| scope.follow(values, context, p, source.pos)).unwrap_or_else(|| context.to_vec());
+				let output = render(values, body, &context, &inner, ignore);
+				if scope.calls.contains(name) { output? } // Only said once for recursive macros
+				else { output.chain_err(|| format!("Failed to render macro {}", name))? }
 			},
 			Node::KeySub(n) => match context.iter().rev().nth(*n as usize).ok_or(Error::from("No key in this context"))? {
-				YamlPathElem::Down(ref k) => k.to_string(),
+				YamlPathElem::Down(ref k) if k == vars::ITEM && scope.partial.is_some() => { // Only known once the section it's in is rendered
+					let (open, close) = scope.partial.unwrap_or_default();
+					print::tag(node, open, close)
+				},
+				YamlPathElem::Down(ref k) => scope.text(k),
 				_ => bail!("KeySub attempted on unexpected path element"),
 			},
 		};
@@ -74,17 +339,26 @@ fn matching_delim(open: &str) -> String { // TODO Not Unicode-aware.  Is it prac
 	open.chars().rev().map(flip).collect()
 }
 
+#[derive(Debug, PartialEq)]
+enum Dialect { Tpl, Mustache }
+
+// What to do with tags that can't be resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ignore { Fail, Blank, Keep }
+
 #[derive(Debug, PartialEq)]
 struct ParseArgs {
 	pub open: String,
 	pub close: String,
-	pub ignore: bool,
+	pub ignore: Ignore,
+	pub dialect: Dialect,
+	pub sort_keys: bool,
 }
 
 impl ParseArgs {
 	fn from_yaml(yaml: &mut Yaml) -> Result<Self> {
 		if let Yaml::Hash(h) = yaml {
-			if let Yaml::Hash(m) = h.entry(Yaml::String("_config".to_string())).or_insert(Yaml::Hash(BTreeMap::new())) {
+			if let Yaml::Hash(m) = h.entry(Yaml::String("_config".to_string())).or_insert(Yaml::Hash(Hash::new())) {
 				let open = match m.entry(Yaml::String("open".to_string())).or_insert(Yaml::String("{{".to_string())) {
 					Yaml::String(s) => s.clone(),
 					_ => bail!("_config.open must be a string"),
@@ -94,10 +368,21 @@ impl ParseArgs {
 					_ => bail!("_config.close must be a string"),
 				};
 				let ignore = match m.entry(Yaml::String("ignore".to_string())).or_insert(Yaml::Boolean(false)) {
-					Yaml::Boolean(b) => b,
-					_ => bail!("_config.ignore must be a boolean"),
+					Yaml::Boolean(false) => Ignore::Fail,
+					Yaml::Boolean(true) => Ignore::Blank,
+					Yaml::String(ref s) if s == "keep" => Ignore::Keep,
+					_ => bail!("_config.ignore must be a boolean or \"keep\""),
+				};
+				let dialect = match m.entry(Yaml::String("dialect".to_string())).or_insert(Yaml::String("tpl".to_string())) {
+					Yaml::String(ref s) if s == "tpl" => Dialect::Tpl,
+					Yaml::String(ref s) if s == "mustache" => Dialect::Mustache,
+					_ => bail!("_config.dialect must be \"tpl\" or \"mustache\""),
 				};
-				Ok(ParseArgs { open: open.to_string(), close: close.to_string(), ignore: *ignore })
+				let sort_keys = match m.entry(Yaml::String("sort_keys".to_string())).or_insert(Yaml::Boolean(false)) {
+					Yaml::Boolean(b) => *b,
+					_ => bail!("_config.sort_keys must be a boolean"),
+				};
+				Ok(ParseArgs { open: open.to_string(), close: close.to_string(), ignore, dialect, sort_keys })
 			}
 			else { bail!("_config must be an object"); }
 		}
@@ -105,28 +390,313 @@ impl ParseArgs {
 	}
 }
 
-fn run() -> Result<()> {
-	let args = clap_app!(tpl =>
-		(about: "Simple multi-purpose template engine")
-		(@arg input: * index(1) "File to be templated")
-		(@arg values: -f [file] "YAML file of template values")
-	).get_matches();
-
-	let input = read_file(args.value_of("input").unwrap()).chain_err(|| "Failed to get input")?; // This unwrap is safe
-	let cli_values = match args.value_of("values").map(|fname|
+fn split_document(spec: &str) -> (&str, Option<usize>) { // Splits `file.yaml#2` into the file name and document number
+	match spec.rfind('#').map(|i| (&spec[..i], spec[i+1..].parse::<usize>())) {
+		Some((fname, Ok(n))) => (fname, Some(n)),
+		_ => (spec, None),
+	}
+}
+
+fn load_template(path: &str) -> Result<(String, Parser, ParseArgs)> { // For tools that work on templates alone, with the settings from their own YAML
+	let input = read_file(path)?;
+	let mut parser = Parser::new(&input);
+	let mut values = yaml::merge(parser.get_yaml()?.into_iter().flatten().collect()).chain_err(|| "Failed to merge values")?;
+	let pargs = ParseArgs::from_yaml(&mut values).chain_err(|| "Error parsing template arguments")?;
+	if pargs.dialect != Dialect::Tpl { bail!(format!("{} is a mustache template, but only tpl templates are supported", path)); }
+	Ok((input, parser, pargs))
+}
+
+fn format_files(args: &ArgMatches) -> Result<()> { // Rewrites templates with normalized tags, or with --check lists the ones that would change
+	let mut unformatted = vec![];
+	for path in args.values_of("files").unwrap() { // This unwrap is safe
+		let (input, mut parser, pargs) = load_template(path)?;
+		let header = input[..input.len() - parser.remaining().len()].to_string(); // The YAML is left as it was written
+		let tree = parser.get_tpl(&pargs.open, &pargs.close).chain_err(|| format!("Failed to parse {}", path))?;
+		let output = header + &print::tree(&tree, &pargs.open, &pargs.close);
+		if output == input { continue; }
+		if args.is_present("check") { unformatted.push(path); }
+		else { fs::write(path, output).chain_err(|| format!("Failed to write to {}", path))?; }
+	}
+	if ! unformatted.is_empty() { bail!(format!("These templates aren't formatted:\n  {}", unformatted.join("\n  "))); }
+	Ok(())
+}
+
+fn lint_files(args: &ArgMatches) -> Result<()> {
+	let mut problems = vec![];
+	for path in args.values_of("files").unwrap() { // This unwrap is safe
+		let (_, mut parser, pargs) = load_template(path)?;
+		let tokens = parser.get_tokens(&pargs.open, &pargs.close).chain_err(|| format!("Failed to parse {}", path))?;
+		problems.extend(lint::lint(&tokens, &pargs.open, &pargs.close).into_iter().map(|(pos, message)| format!("{}:{}: {}", path, pos, message)));
+	}
+	if ! problems.is_empty() { bail!(format!("Found {} problems:\n  {}", problems.len(), problems.join("\n  "))); }
+	Ok(())
+}
+
+fn generate(args: &ArgMatches, files: &RefCell<Vec<String>>) -> Result<String> { // Everything a run outputs, and the files it read in files
+	let input_path = args.value_of("input").unwrap(); // This unwrap is safe
+	files.borrow_mut().extend(vec![Some(input_path), args.value_of("values").map(|spec| split_document(spec).0), args.value_of("schema")].into_iter().flatten().map(|f| f.to_string()));
+	let input = read_file(input_path).chain_err(|| "Failed to get input")?;
+	let cli_values = match args.value_of("values").map(|spec| {
+		let (fname, document) = split_document(spec);
 		read_file(fname).chain_err(|| "Failed to read values file")
-		.and_then(|yaml| yaml_rust::YamlLoader::load_from_str(&yaml).chain_err(|| "Failed to parse values file"))
-	) {
+		.and_then(|yaml| yaml::load(&yaml, document).chain_err(|| "Failed to parse values file"))
+	}) {
 		Some(res) => Some(res?), // Pending Option::transpose()
 		None => None,
 	};
+	let json_schema = match args.value_of("schema").map(|fname| {
+		read_file(fname).chain_err(|| "Failed to read JSON Schema")
+		.and_then(|text| yaml::load(&text, Some(0)).chain_err(|| "Failed to parse JSON Schema"))
+	}) {
+		Some(res) => res?.pop(),
+		None => None,
+	};
 	let mut parser = Parser::new(&input);
-	let mut values = yaml::merge(vec![parser.get_yaml()?, cli_values].into_iter().flat_map(|x| x).flat_map(|x| x).collect()); // TODO Convert flat_map() to flatten() when it becomes stable
+	let mut values = yaml::merge(vec![parser.get_yaml()?, cli_values].into_iter().flatten().flatten().collect()).chain_err(|| "Failed to merge values")?;
 	let pargs = ParseArgs::from_yaml(&mut values).chain_err(|| "Error parsing template arguments")?;
-	print!("{}", render(&values, &parser.get_tpl(&pargs.open, &pargs.close)?, &vec![], pargs.ignore)?);
+	if args.is_present("list_vars") || args.is_present("skeleton") {
+		if pargs.dialect != Dialect::Tpl { bail!("Only tpl templates can be analyzed"); }
+		let vars = vars::collect(&parser.get_tpl(&pargs.open, &pargs.close)?);
+		return if args.is_present("skeleton") { vars::skeleton(&vars) } else { Ok(vars::list(&vars)) };
+	}
+	if args.is_present("dump_ast") {
+		if pargs.dialect != Dialect::Tpl { bail!("Only tpl templates can be dumped"); }
+		return Ok(dump::ast(&parser.get_tpl(&pargs.open, &pargs.close)?));
+	}
+	if args.is_present("dump_values") { // Before validating, since the values are most worth seeing when they're wrong
+		schema::defaults(&mut values)?;
+		if pargs.sort_keys { yaml::sort_keys(&mut values); }
+		return dump::values(&values);
+	}
+	schema::validate(&mut values)?;
+	if let Some(json_schema) = json_schema {
+		let mut checked = values.clone();
+		if let Yaml::Hash(ref mut h) = checked { // Only the values themselves are checked, not the settings
+			for key in &["_config", "_schema"] { h.remove(&Yaml::String(key.to_string())); }
+		}
+		jsonschema::Validator::new(json_schema).validate(&checked)?;
+	}
+	if pargs.sort_keys { yaml::sort_keys(&mut values); }
+	let report = RefCell::new(Report::default());
+	let trace = RefCell::new(Trace::default());
+	let output = match pargs.dialect {
+		Dialect::Tpl => {
+			let scope = Scope {
+				report: if args.is_present("report") { Some(&report) } else { None },
+				partial: if args.is_present("partial") { Some((&pargs.open, &pargs.close)) } else { None },
+				trace: if args.is_present("trace") { Some(&trace) } else { None },
+				..Scope::default()
+			};
+			let output = render(&values, &parser.get_tpl(&pargs.open, &pargs.close)?, &vec![], &scope, pargs.ignore);
+			eprint!("{}", trace.borrow().lines(input_path)); // Before any error, which the trace may explain
+			let output = output?;
+			if scope.partial.is_some() { print::delimiters(&pargs.open, &pargs.close) + &output } else { output }
+		},
+		Dialect::Mustache if args.is_present("report") || args.is_present("partial") || args.is_present("trace") => bail!("--report, --partial, and --trace only work with tpl templates"),
+		Dialect::Mustache => render_mustache(input_path, &parser.get_text()?, &values, &pargs, files)?,
+	};
+	if let Some(summary) = report.borrow().summary(&values, input_path).filter(|_| args.is_present("report")) {
+		if args.is_present("strict") { bail!(summary); }
+		eprintln!("Warning: {}", summary);
+	}
+	Ok(output)
+}
+
+fn render_mustache(input_path: &str, text: &str, values: &Yaml, pargs: &ParseArgs, files: &RefCell<Vec<String>>) -> Result<String> {
+	let dir = Path::new(input_path).parent().unwrap_or_else(|| Path::new(""));
+	let partials = |name: &str| -> Result<Option<String>> { // Partials are files next to the template, with or without a .mustache extension
+		for path in &[dir.join(name), dir.join(format!("{}.mustache", name))] {
+			files.borrow_mut().push(path.to_string_lossy().to_string()); // Even if it doesn't exist, since creating it changes the output
+			if path.is_file() { return read_file(&path.to_string_lossy()).map(Some); }
+		}
+		Ok(None)
+	};
+	mustache::render(text, values, &pargs.open, &pargs.close, &partials)
+}
+
+fn write_output(args: &ArgMatches, output: &str) -> Result<()> {
+	match args.value_of("output") {
+		Some(path) if read_file(path).ok().as_ref().map(|s| &s[..]) == Some(output) => Ok(()), // Left alone so its modification time doesn't change
+		Some(path) => fs::write(path, output).chain_err(|| format!("Failed to write to {}", path)),
+		None => {
+			print!("{}", output);
+			Ok(())
+		},
+	}
+}
+
+fn diff(path: &str, existing: &str, output: &str) -> String { // From the file as it is to what would be rendered
+	TextDiff::from_lines(existing, output).unified_diff().header(path, &format!("{} (rendered)", path)).to_string()
+}
+
+fn compare_output(args: &ArgMatches, output: &str, check: bool) -> Result<()> { // For --check and --diff, which leave the output file as it is
+	let path = args.value_of("output").unwrap(); // This unwrap is safe, since -o is required wherever these are used
+	let existing = if Path::new(path).exists() { Some(read_file(path)?) } else { None }; // A missing file is out of date even if the output is empty
+	if existing.as_ref().map(|s| &s[..]) == Some(output) { return Ok(()); }
+	if args.is_present("diff") { print!("{}", diff(path, existing.as_ref().map(|s| &s[..]).unwrap_or_default(), output)); }
+	if check {
+		bail!(match existing {
+			Some(_) => format!("{} doesn't match what the template renders", path),
+			None => format!("{} doesn't exist, so it isn't what the template renders", path),
+		});
+	}
 	Ok(())
 }
 
+fn watched(path: &str) -> Option<PathBuf> { // How a file is named in change events from watching its directory
+	let path = Path::new(path);
+	let dir = match path.parent() {
+		Some(dir) if dir != Path::new("") => dir,
+		_ => Path::new("."),
+	};
+	Some(dir.canonicalize().ok()?.join(path.file_name()?))
+}
+
+fn watch(args: &ArgMatches) -> Result<()> { // Renders again whenever a file the last run read changes, until interrupted
+	let (tx, rx) = channel();
+	let mut watcher = notify::watcher(tx, Duration::from_millis(100)).chain_err(|| "Failed to start watching files")?;
+	let mut dirs = vec![];
+	loop {
+		let files = RefCell::new(vec![]);
+		match generate(args, &files).and_then(|output| write_output(args, &output)) {
+			Ok(()) => eprintln!("Rendered {}", args.value_of("input").unwrap_or_default()),
+			Err(e) => eprint!("{}", e.display_chain()), // Reported without exiting, to be fixed before the next change
+		}
+		let files = files.into_inner().iter().filter_map(|f| watched(f)).collect::<Vec<PathBuf>>();
+		for dir in files.iter().filter_map(|f| f.parent()) { // Directories are watched rather than files, since editors often replace files when saving
+			if dirs.iter().any(|d| d == dir) { continue; }
+			watcher.watch(dir, RecursiveMode::NonRecursive).chain_err(|| format!("Failed to watch {}", dir.display()))?;
+			dirs.push(dir.to_path_buf());
+		}
+		loop {
+			let changed = match rx.recv().chain_err(|| "Stopped watching files")? {
+				DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Remove(path) | DebouncedEvent::Rename(_, path) => path,
+				DebouncedEvent::Error(e, _) => bail!(format!("Failed while watching files: {}", e)),
+				_ => continue,
+			};
+			if files.contains(&changed) { break; }
+		}
+	}
+}
+
+fn input_args() -> Vec<Arg<'static, 'static>> { // What every way of rendering takes
+	vec![
+		Arg::with_name("input").index(1).required(true).help("File to be templated"),
+		Arg::with_name("values").short("f").takes_value(true).value_name("file").help("YAML file of template values, with #N to use only its Nth document (counting from 0)"),
+		Arg::with_name("schema").long("schema").takes_value(true).value_name("file").help("JSON Schema that the values must match"),
+		Arg::with_name("report").long("report").help("Warn about values that were never used and substitutions of values that were missing"),
+		Arg::with_name("strict").long("strict").requires("report").help("Make the problems found by --report errors"),
+		Arg::with_name("trace").long("trace").help("Print each value looked up and what each section did while rendering, to standard error"),
+		Arg::with_name("partial").long("partial").help("Output a template with the tags that can't be resolved yet left in, for rendering again with more values"),
+	]
+}
+
+fn render_args() -> Vec<Arg<'static, 'static>> { // For `tpl render`, which is also what runs without a subcommand
+	vec![
+		Arg::with_name("output").short("o").takes_value(true).value_name("file").help("File to write the output to, which is left alone if it wouldn't change"),
+		Arg::with_name("watch").long("watch").requires("output").help("Keep running, and render again whenever the template or a file it uses changes"),
+		Arg::with_name("check").long("check").requires("output").conflicts_with("watch").help("Fail if the output file isn't what would be rendered, without writing it"),
+		Arg::with_name("diff").long("diff").requires("output").conflicts_with("watch").help("Print how the output file differs from what would be rendered, without writing it"),
+		Arg::with_name("list_vars").long("list-vars").conflicts_with("skeleton").help("List the value paths the template uses instead of rendering it"),
+		Arg::with_name("skeleton").long("skeleton").help("Output a YAML values file with the paths the template uses instead of rendering it"),
+		Arg::with_name("dump_ast").long("dump-ast").conflicts_with_all(&["dump_values", "list_vars", "skeleton"]).help("Print the parsed template instead of rendering it"),
+		Arg::with_name("dump_values").long("dump-values").conflicts_with_all(&["list_vars", "skeleton"]).help("Print the merged values, with defaults filled in, instead of rendering"),
+	]
+}
+
+fn subcommands() -> Vec<App<'static, 'static>> {
+	vec![
+		App::new("render").about("Renders a template, which is also what happens without a subcommand").args(&input_args()).args(&render_args()),
+		App::new("check").about("Fails if an output file isn't what its template renders, without writing it").args(&input_args())
+			.arg(Arg::with_name("output").short("o").takes_value(true).value_name("file").required(true).help("File to compare the output with"))
+			.arg(Arg::with_name("diff").long("diff").help("Print how the file differs from what would be rendered")),
+		clap_app!(vars =>
+			(about: "Lists the value paths a template uses, without any values")
+			(@arg input: * "Template to analyze")
+			(@arg skeleton: --skeleton "Output a YAML values file with the paths set to null instead")
+		),
+		clap_app!(fmt =>
+			(about: "Rewrites templates with consistent spacing in tags")
+			(@arg files: * ... "Templates to format in place")
+			(@arg check: --check "Only list the templates that aren't formatted, failing if there are any")
+		),
+		clap_app!(lint =>
+			(about: "Checks templates for likely mistakes without rendering them")
+			(@arg files: * ... "Templates to check")
+		),
+		clap_app!(build =>
+			(about: "Renders every job listed in a manifest whose output is older than its inputs")
+			(@arg manifest: default_value("tpl.yaml") "Manifest listing the jobs")
+			(@arg force: --force "Render every job, even if its output is up to date")
+		),
+		clap_app!(completions =>
+			(about: "Prints a shell completion script")
+			(@arg shell: * possible_value[bash zsh fish] "Shell to complete tpl in")
+		),
+		clap_app!(man =>
+			(about: "Prints a man page in roff format")
+		),
+	]
+}
+
+fn app() -> App<'static, 'static> {
+	App::new("tpl").version(crate_version!()).about("Simple multi-purpose template engine")
+		.setting(AppSettings::SubcommandsNegateReqs)
+		.args(&input_args()).args(&render_args())
+		.subcommands(subcommands())
+}
+
+fn man() -> Result<String> { // The help for the program and every subcommand, as preformatted text
+	let help = |mut app: App| -> Result<String> {
+		let mut ret = vec![];
+		app.write_long_help(&mut ret).chain_err(|| "Failed to write help")?;
+		Ok(String::from_utf8_lossy(&ret).lines().map(|line| match line.replace('\\', "\\e") {
+			ref line if line.starts_with('.') || line.starts_with('\'') => format!("\\&{}\n", line), // Otherwise read as requests
+			line => format!("{}\n", line),
+		}).collect())
+	};
+	let mut ret = format!(".TH TPL 1 \"\" \"tpl {}\"\n.SH NAME\ntpl \\- Simple multi-purpose template engine\n.SH DESCRIPTION\n.nf\n{}.fi\n", crate_version!(), help(app())?);
+	for sub in subcommands() {
+		let name = sub.get_name().to_string();
+		ret.push_str(&format!(".SH \"TPL {}\"\n.nf\n{}.fi\n", name.to_uppercase(), help(sub.bin_name(format!("tpl {}", name)))?));
+	}
+	Ok(ret)
+}
+
+fn render_command(args: &ArgMatches, check: bool) -> Result<()> {
+	if args.is_present("watch") { return watch(args); }
+	let output = generate(args, &RefCell::new(vec![]))?;
+	if check || args.is_present("check") || args.is_present("diff") { compare_output(args, &output, check || args.is_present("check")) }
+	else { write_output(args, &output) }
+}
+
+fn run() -> Result<()> {
+	let args = app().get_matches();
+	match args.subcommand() {
+		("render", Some(args)) => render_command(args, false),
+		("check", Some(args)) => render_command(args, true),
+		("vars", Some(args)) => {
+			let (_, mut parser, pargs) = load_template(args.value_of("input").unwrap())?; // This unwrap is safe
+			let vars = vars::collect(&parser.get_tpl(&pargs.open, &pargs.close)?);
+			print!("{}", if args.is_present("skeleton") { vars::skeleton(&vars)? } else { vars::list(&vars) });
+			Ok(())
+		},
+		("fmt", Some(args)) => format_files(args),
+		("lint", Some(args)) => lint_files(args),
+		("build", Some(args)) => build::build(args.value_of("manifest").unwrap(), args.is_present("force")), // This unwrap is safe, since there's a default
+		("completions", Some(args)) => {
+			let shell = args.value_of("shell").unwrap_or_default().parse::<Shell>().map_err(Error::from)?;
+			app().gen_completions_to("tpl", shell, &mut io::stdout());
+			Ok(())
+		},
+		("man", _) => {
+			print!("{}", man()?);
+			Ok(())
+		},
+		_ => render_command(&args, false),
+	}
+}
+
 quick_main!(run);
 
 #[cfg(test)]
@@ -141,31 +711,137 @@ mod tests {
 		assert_eq!(matching_delim("« "), " «".to_string());
 	}
 	#[test]
+	fn man_page() {
+		let man = super::man().unwrap();
+		assert!(man.starts_with(".TH TPL 1 "));
+		for section in &["NAME", "DESCRIPTION", "\"TPL RENDER\"", "\"TPL CHECK\"", "\"TPL VARS\"", "\"TPL FMT\"", "\"TPL LINT\""] {
+			assert!(man.contains(&format!("\n.SH {}\n", section)));
+		}
+		assert!(man.contains("USAGE:\n    tpl fmt [FLAGS] <files>...\n"));
+		assert!(! man.contains("\n.\n") && ! man.lines().any(|line| line.starts_with('\'')));
+	}
+	fn temp_dir(name: &str) -> ::std::path::PathBuf {
+		let dir = ::std::env::temp_dir().join(format!("tpl-{}-{}", name, ::std::process::id()));
+		::std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+	#[test]
+	fn watched_paths() { // Tests run in the crate's directory
+		use super::watched;
+		let cwd = ::std::env::current_dir().unwrap().canonicalize().unwrap();
+		assert_eq!(watched("Cargo.toml"), Some(cwd.join("Cargo.toml")));
+		assert_eq!(watched("test/test.tpl"), Some(cwd.join("test").join("test.tpl")));
+		assert_eq!(watched("./test/../test/new.tpl"), Some(cwd.join("test").join("new.tpl"))); // Files that don't exist yet can still be created
+		assert_eq!(watched("nope/test.tpl"), None);
+	}
+	#[test]
+	fn write_output_unchanged() {
+		use std::time::{Duration, SystemTime};
+		let dir = temp_dir("write");
+		let path = dir.join("out.txt");
+		let name = path.to_string_lossy().to_string();
+		let args = super::app().get_matches_from(vec!["tpl", "in.tpl", "-o", &name]);
+		let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+		::std::fs::write(&path, "a").unwrap();
+		::std::fs::File::options().write(true).open(&path).unwrap().set_modified(old).unwrap();
+		super::write_output(&args, "a").unwrap();
+		assert_eq!(::std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+		super::write_output(&args, "b").unwrap();
+		assert_eq!(super::read_file(&name).unwrap(), "b");
+		assert!(::std::fs::metadata(&path).unwrap().modified().unwrap() > old);
+		::std::fs::remove_dir_all(&dir).unwrap();
+	}
+	#[test]
+	fn generate_files() { // Everything that was read or could have been, for --watch
+		let dir = temp_dir("generate");
+		let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+		::std::fs::write(file("in.tpl"), "---\n_config: {dialect: mustache}\n...\n{{>a}}{{>b}}").unwrap();
+		::std::fs::write(file("b.mustache"), "{{x}}").unwrap();
+		::std::fs::write(file("v.yaml"), "x: 1").unwrap();
+		let args = super::app().get_matches_from(vec!["tpl".to_string(), file("in.tpl"), "-f".to_string(), file("v.yaml#0")]);
+		let files = ::std::cell::RefCell::new(vec![]);
+		assert_eq!(super::generate(&args, &files).unwrap(), "1");
+		assert_eq!(files.into_inner(), vec![file("in.tpl"), file("v.yaml"), file("a"), file("a.mustache"), file("b"), file("b.mustache")]);
+		::std::fs::remove_dir_all(&dir).unwrap();
+	}
+	#[test]
+	fn generate_dump_values() { // Even when they don't match the schemas, since that's when they're worth seeing
+		let dir = temp_dir("dump");
+		let file = |name: &str| dir.join(name).to_string_lossy().to_string();
+		::std::fs::write(file("in.tpl"), "---\n_schema: {port: {type: integer, default: 80}, host: {type: string}}\nhost: 1\n...\n").unwrap();
+		::std::fs::write(file("s.json"), "{\"required\": [\"name\"]}").unwrap();
+		let args = super::app().get_matches_from(vec!["tpl".to_string(), file("in.tpl"), "--schema".to_string(), file("s.json"), "--dump-values".to_string()]);
+		let output = super::generate(&args, &::std::cell::RefCell::new(vec![])).unwrap();
+		assert!(output.contains("host: 1\n") && output.contains("port: 80\n"));
+		::std::fs::remove_dir_all(&dir).unwrap();
+	}
+	#[test]
+	fn compare_output_basic() {
+		let dir = temp_dir("compare");
+		let path = dir.join("out.txt");
+		let name = path.to_string_lossy().to_string();
+		let args = |extra: &[&str]| super::app().get_matches_from(vec!["tpl", "in.tpl", "-o", &name].into_iter().chain(extra.iter().cloned()));
+		let message = |r: super::Result<()>| r.unwrap_err().to_string();
+		assert_eq!(message(super::compare_output(&args(&[]), "", true)), format!("{} doesn't exist, so it isn't what the template renders", name));
+		::std::fs::write(&path, "a\nb\n").unwrap();
+		super::compare_output(&args(&["--check"]), "a\nb\n", true).unwrap();
+		assert_eq!(message(super::compare_output(&args(&["--diff"]), "a\nc\n", true)), format!("{} doesn't match what the template renders", name));
+		super::compare_output(&args(&["--diff"]), "a\nc\n", false).unwrap(); // --diff alone doesn't fail
+		assert_eq!(super::read_file(&name).unwrap(), "a\nb\n");
+		assert_eq!(super::diff("out.txt", "a\nb\n", "a\nc\n"), "--- out.txt\n+++ out.txt (rendered)\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n");
+		::std::fs::remove_dir_all(&dir).unwrap();
+	}
+	#[test]
+	fn split_document_basic() {
+		use super::split_document;
+		assert_eq!(split_document("a.yaml"), ("a.yaml", None));
+		assert_eq!(split_document("a.yaml#2"), ("a.yaml", Some(2)));
+		assert_eq!(split_document("a#b.yaml#0"), ("a#b.yaml", Some(0)));
+		assert_eq!(split_document("a#b.yaml"), ("a#b.yaml", None));
+	}
+	#[test]
 	fn from_yaml_basic() {
 		use super::yaml::merge;
-		use super::ParseArgs;
+		use super::{ParseArgs, Dialect, Ignore};
 		use ::yaml_rust::YamlLoader;
-		fn do_test(input: &str, open: &str, close: &str, ignore: bool) {
-			assert_eq!(ParseArgs::from_yaml(&mut merge(YamlLoader::load_from_str(input).unwrap())).unwrap(), ParseArgs { open: open.to_string(), close: close.to_string(), ignore: ignore });
+		fn do_test(input: &str, open: &str, close: &str, ignore: Ignore, dialect: Dialect, sort_keys: bool) {
+			assert_eq!(ParseArgs::from_yaml(&mut merge(YamlLoader::load_from_str(input).unwrap()).unwrap()).unwrap(), ParseArgs { open: open.to_string(), close: close.to_string(), ignore, dialect, sort_keys });
 		}
-		do_test("", "{{", "}}", false);
-		do_test("_config:\n  open: <[", "<[", "]>", false);
-		do_test("_config:\n  ignore: true", "{{", "}}", true);
-		do_test("_config:\n  open: \"[\"\n  close: blah\nopen: )", "[", "blah", false);
+		do_test("", "{{", "}}", Ignore::Fail, Dialect::Tpl, false);
+		do_test("_config:\n  open: <[", "<[", "]>", Ignore::Fail, Dialect::Tpl, false);
+		do_test("_config:\n  ignore: true", "{{", "}}", Ignore::Blank, Dialect::Tpl, false);
+		do_test("_config:\n  ignore: keep", "{{", "}}", Ignore::Keep, Dialect::Tpl, false);
+		do_test("_config:\n  open: \"[\"\n  close: blah\nopen: )", "[", "blah", Ignore::Fail, Dialect::Tpl, false);
+		do_test("_config:\n  dialect: mustache", "{{", "}}", Ignore::Fail, Dialect::Mustache, false);
+		do_test("_config:\n  sort_keys: true", "{{", "}}", Ignore::Fail, Dialect::Tpl, true);
+		assert!(ParseArgs::from_yaml(&mut merge(YamlLoader::load_from_str("_config:\n  sort_keys: 1").unwrap()).unwrap()).is_err());
+		assert!(ParseArgs::from_yaml(&mut merge(YamlLoader::load_from_str("_config:\n  dialect: handlebars").unwrap()).unwrap()).is_err());
 	}
 	#[test]
 	fn render_ignore() {
-		use super::render;
+		use super::{render, Scope, Ignore};
 		use ::Yaml;
 		use super::parse::Node;
 		use super::parse::YamlPathElem::*;
-		let tpl = vec![Node::DirectSub(vec![Down("x".to_string())])];
-		assert!(render(&Yaml::Null, &tpl, &vec![], true).is_ok());
-		assert!(render(&Yaml::Null, &tpl, &vec![], false).is_err());
-		assert!(render(&Yaml::Hash(vec![(Yaml::String("x".to_string()), Yaml::String("y".to_string()))].into_iter().collect()), &tpl, &vec![], false).is_ok());
-		assert!(render(&Yaml::Hash(vec![(Yaml::String("x".to_string()), Yaml::Array(vec![Yaml::Integer(1)]))].into_iter().collect()), &tpl, &vec![], false).is_err());
-		assert!(render(&Yaml::Null, &vec![Node::KeySub(10)], &vec![], false).is_err());
+		let tpl = vec![Node::DirectSub(::expr::Expr::Path(vec![Down("x".to_string())]), None, Default::default())];
+		assert!(render(&Yaml::Null, &tpl, &vec![], &Scope::default(), Ignore::Blank).is_ok());
+		assert!(render(&Yaml::Null, &tpl, &vec![], &Scope::default(), Ignore::Fail).is_err());
+		assert!(render(&Yaml::Hash(vec![(Yaml::String("x".to_string()), Yaml::String("y".to_string()))].into_iter().collect()), &tpl, &vec![], &Scope::default(), Ignore::Fail).is_ok());
+		assert!(render(&Yaml::Hash(vec![(Yaml::String("x".to_string()), Yaml::Array(vec![Yaml::Integer(1)]))].into_iter().collect()), &tpl, &vec![], &Scope::default(), Ignore::Fail).is_err());
+		assert!(render(&Yaml::Null, &[Node::KeySub(10)], &vec![], &Scope::default(), Ignore::Fail).is_err());
+	}
+	#[test]
+	fn render_macro_errors() {
+		use super::{render, Scope, Ignore};
+		use ::Yaml;
+		use super::parse::Node;
+		assert!(render(&Yaml::Null, &[Node::Call("m".to_string(), None, vec![], Default::default())], &vec![], &Scope::default(), Ignore::Fail).is_err());
+		assert!(render(&Yaml::Null, &[Node::Define("m".to_string(), vec![]), Node::Define("m".to_string(), vec![])], &vec![], &Scope::default(), Ignore::Fail).is_err());
+		assert!(render(&Yaml::Null, &[Node::Define("m".to_string(), vec![Node::Define("m".to_string(), vec![])]), Node::Call("m".to_string(), None, vec![], Default::default())], &vec![], &Scope::default(), Ignore::Fail).is_ok());
+		let forever = render(&Yaml::Null, &[Node::Define("m".to_string(), vec![Node::Call("m".to_string(), None, vec![], Default::default())]), Node::Call("m".to_string(), None, vec![], Default::default())], &vec![], &Scope::default(), Ignore::Fail);
+		assert!(forever.unwrap_err().iter().any(|e| e.to_string() == "Macro m is nested more than 100 calls deep, so it probably calls itself forever"));
 	}
 }
 
 #[cfg(test)] mod inttests;
+#[cfg(test)] mod spectests;
@@ -0,0 +1,298 @@
+use ::yaml_rust::Yaml;
+use ::errors::*;
+
+// Rendering for `_config.dialect: mustache`, which follows the Mustache spec (and mustache.js where the spec is silent) rather
+// than tpl's own semantics.  The tokenizer is separate from the one in `parse` because standalone lines require looking at whole
+// lines of the template at once.
+
+#[derive(Debug, PartialEq)]
+enum Token {
+	Text(String),
+	Var(String, bool), // Second argument true to escape HTML
+	Section(String),
+	Inverted(String),
+	End(String),
+	Partial(String, String), // Second argument is the indentation of a standalone partial
+	Comment,
+	SetDelim,
+}
+
+#[derive(Default)]
+struct Line {
+	spaces: Vec<usize>, // Indices of whitespace text tokens on the current line
+	tags: Vec<usize>, // Indices of tags that may stand alone on the current line
+	non_space: bool,
+}
+
+fn strip_standalone(tokens: &mut [Option<Token>], line: &mut Line) {
+	if ! line.tags.is_empty() && ! line.non_space {
+		for &t in line.tags.iter() {
+			let prefix = line.spaces.iter().filter(|&&i| i < t).map(|&i| match tokens[i] {
+				Some(Token::Text(ref s)) if s != "\r" && s != "\n" => s.to_string(),
+				_ => "".to_string(),
+			}).collect::<String>();
+			if let Some(Token::Partial(_, ref mut indent)) = tokens[t] { *indent = prefix; }
+		}
+		for &i in line.spaces.iter() { tokens[i] = None; }
+	}
+	*line = Line::default();
+}
+
+fn tokenize(input: &str, open: &str, close: &str) -> Result<Vec<Token>> {
+	let (mut open, mut close) = (open.to_string(), close.to_string());
+	let mut tokens: Vec<Option<Token>> = vec![];
+	let mut line = Line::default();
+	let mut rest = input;
+	loop {
+		let text = match rest.find(&open[..]) { Some(i) => &rest[..i], None => rest };
+		for c in text.chars() { // Text is split into characters so that whitespace can be removed from standalone lines
+			if c.is_whitespace() { line.spaces.push(tokens.len()); }
+			else { line.non_space = true; }
+			tokens.push(Some(Token::Text(c.to_string())));
+			if c == '\n' { strip_standalone(&mut tokens, &mut line); }
+		}
+		if text.len() == rest.len() { break; }
+		rest = &rest[text.len() + open.len()..];
+		let (sigil, end) = match rest.chars().next() {
+			Some('{') => ('{', format!("}}{}", close)),
+			Some('=') => ('=', format!("={}", close)),
+			Some(c) if "&#^/>!".contains(c) => (c, close.to_string()),
+			_ => (' ', close.to_string()),
+		};
+		let start = if sigil == ' ' { 0 } else { 1 };
+		let len = rest[start..].find(&end[..]).ok_or_else(|| Error::from(format!("Unclosed tag at \"{}{}\"", open, rest.chars().take(20).collect::<String>())))?;
+		let content = &rest[start..start + len];
+		rest = &rest[start + len + end.len()..];
+		let name = content.trim().to_string();
+		if name.is_empty() && sigil != '!' { bail!("Empty tag"); }
+		let token = match sigil {
+			'{' | '&' => Token::Var(name, false),
+			'#' => Token::Section(name),
+			'^' => Token::Inverted(name),
+			'/' => Token::End(name),
+			'>' => Token::Partial(name, "".to_string()),
+			'!' => Token::Comment,
+			'=' => {
+				let delims = content.split_whitespace().collect::<Vec<&str>>();
+				if delims.len() != 2 { bail!(format!("Invalid delimiter change \"{}\"", content)); }
+				open = delims[0].to_string();
+				close = delims[1].to_string();
+				Token::SetDelim
+			},
+			_ => Token::Var(name, true),
+		};
+		match token {
+			Token::Var(..) => line.non_space = true,
+			_ => line.tags.push(tokens.len()),
+		}
+		tokens.push(Some(token));
+	}
+	strip_standalone(&mut tokens, &mut line);
+	let mut ret = vec![];
+	for token in tokens.into_iter().flatten() { // Join the single characters back together
+		match (ret.last_mut(), token) {
+			(Some(Token::Text(ref mut acc)), Token::Text(ref s)) => acc.push_str(s),
+			(_, token) => ret.push(token),
+		}
+	}
+	Ok(ret)
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+	Text(String),
+	Var(String, bool),
+	Section(String, bool, Vec<Node>), // Second argument false to invert the condition
+	Partial(String, String),
+}
+
+fn build_tree<I: Iterator<Item=Token>>(tokens: &mut I, section: Option<&str>) -> Result<Vec<Node>> {
+	let mut ret = vec![];
+	while let Some(token) = tokens.next() {
+		match token {
+			Token::Text(s) => ret.push(Node::Text(s)),
+			Token::Var(name, escape) => ret.push(Node::Var(name, escape)),
+			Token::Section(name) => { let children = build_tree(tokens, Some(&name))?; ret.push(Node::Section(name, true, children)); },
+			Token::Inverted(name) => { let children = build_tree(tokens, Some(&name))?; ret.push(Node::Section(name, false, children)); },
+			Token::End(name) => match section {
+				Some(open) if open == name => return Ok(ret),
+				Some(open) => bail!(format!("Section {} closed by {}", open, name)),
+				None => bail!(format!("Unopened section {} closed", name)),
+			},
+			Token::Partial(name, indent) => ret.push(Node::Partial(name, indent)),
+			Token::Comment | Token::SetDelim => (),
+		}
+	}
+	match section {
+		Some(open) => bail!(format!("Unclosed section {}", open)),
+		None => Ok(ret),
+	}
+}
+
+fn parse(input: &str, open: &str, close: &str) -> Result<Vec<Node>> {
+	build_tree(&mut tokenize(input, open, close)?.into_iter(), None)
+}
+
+fn child<'a>(yaml: &'a Yaml, key: &str) -> Option<&'a Yaml> {
+	match yaml {
+		Yaml::Hash(ref map) => map.get(&Yaml::String(key.to_string())),
+		Yaml::Array(ref arr) => key.parse::<usize>().ok().and_then(|i| arr.get(i)),
+		_ => None,
+	}
+}
+
+fn lookup<'a>(stack: &[&'a Yaml], name: &str) -> Option<&'a Yaml> {
+	if name == "." { return stack.last().cloned(); }
+	let mut parts = name.split('.');
+	let first = parts.next().unwrap_or(""); // split() always yields at least one item
+	let start = stack.iter().rev().filter_map(|ctx| child(ctx, first)).next();
+	parts.fold(start, |cur, part| cur.and_then(|x| child(x, part)))
+}
+
+fn truthy(yaml: &Yaml) -> bool { // Follows JavaScript, so that templates behave the same as under mustache.js
+	match yaml {
+		Yaml::BadValue | Yaml::Null | Yaml::Boolean(false) | Yaml::Integer(0) => false,
+		Yaml::Real(ref x) => x.parse::<f64>().map(|f| f != 0.0 && ! f.is_nan()).unwrap_or(true),
+		Yaml::String(ref s) => ! s.is_empty(),
+		Yaml::Array(ref a) => ! a.is_empty(),
+		_ => true,
+	}
+}
+
+fn string(yaml: &Yaml) -> String {
+	match yaml {
+		Yaml::Real(ref x) => x.parse::<f64>().map(|f| f.to_string()).unwrap_or_else(|_| x.to_string()),
+		Yaml::Integer(x) => x.to_string(),
+		Yaml::String(ref x) => x.to_string(),
+		Yaml::Boolean(x) => x.to_string(),
+		Yaml::Array(ref a) => a.iter().map(string).collect::<Vec<String>>().join(","),
+		_ => "".to_string(),
+	}
+}
+
+fn escape(s: &str) -> String {
+	s.chars().map(|c| match c {
+		'&' => "&amp;".to_string(),
+		'<' => "&lt;".to_string(),
+		'>' => "&gt;".to_string(),
+		'"' => "&quot;".to_string(),
+		'\'' => "&#39;".to_string(),
+		'/' => "&#x2F;".to_string(),
+		'`' => "&#x60;".to_string(),
+		'=' => "&#x3D;".to_string(),
+		c => c.to_string(),
+	}).collect()
+}
+
+fn indent(s: &str, indent: &str) -> String {
+	let mut ret = indent.to_string();
+	let mut chars = s.chars().peekable();
+	while let Some(c) = chars.next() {
+		ret.push(c);
+		if c == '\n' && chars.peek().is_some() { ret.push_str(indent); }
+	}
+	ret
+}
+
+pub type PartialLoader<'a> = &'a dyn Fn(&str) -> Result<Option<String>>;
+
+struct Env<'a> {
+	open: &'a str,
+	close: &'a str,
+	partials: PartialLoader<'a>,
+}
+
+fn render_tree(tree: &[Node], stack: &mut Vec<&Yaml>, env: &Env) -> Result<String> {
+	let mut ret = "".to_string();
+	for node in tree {
+		match node {
+			Node::Text(ref s) => ret.push_str(s),
+			Node::Var(ref name, escaped) => {
+				let value = lookup(stack, name).map(string).unwrap_or_default();
+				ret.push_str(&if *escaped { escape(&value) } else { value });
+			},
+			Node::Section(ref name, direct, ref children) => {
+				let target = lookup(stack, name).unwrap_or(&Yaml::BadValue);
+				match (truthy(target), *direct, target) {
+					(true, true, Yaml::Array(ref items)) => for item in items {
+						stack.push(item);
+						ret.push_str(&render_tree(children, stack, env)?);
+						stack.pop();
+					},
+					(true, true, _) => {
+						stack.push(target);
+						ret.push_str(&render_tree(children, stack, env)?);
+						stack.pop();
+					},
+					(false, false, _) => ret.push_str(&render_tree(children, stack, env)?),
+					_ => (),
+				}
+			},
+			Node::Partial(ref name, ref prefix) => if let Some(src) = (env.partials)(name).chain_err(|| format!("Failed to load partial {}", name))? {
+				let tree = parse(&indent(&src, prefix), env.open, env.close).chain_err(|| format!("Failed to parse partial {}", name))?;
+				ret.push_str(&render_tree(&tree, stack, env)?);
+			},
+		}
+	}
+	Ok(ret)
+}
+
+pub fn render(input: &str, values: &Yaml, open: &str, close: &str, partials: PartialLoader) -> Result<String> {
+	let tree = parse(input, open, close)?;
+	render_tree(&tree, &mut vec![values], &Env { open, close, partials })
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn tokenize_standalone() {
+		assert_eq!(tokenize("a\n  {{#x}}\nb\n{{/x}}", "{{", "}}").unwrap(), vec![
+			Token::Text("a\n".to_string()),
+			Token::Section("x".to_string()),
+			Token::Text("b\n".to_string()),
+			Token::End("x".to_string()),
+		]);
+		assert_eq!(tokenize(" {{x}} {{!y}}\n", "{{", "}}").unwrap(), vec![
+			Token::Text(" ".to_string()),
+			Token::Var("x".to_string(), true),
+			Token::Text(" ".to_string()),
+			Token::Comment,
+			Token::Text("\n".to_string()),
+		]);
+		assert_eq!(tokenize("\t{{> p }}\r\n", "{{", "}}").unwrap(), vec![Token::Partial("p".to_string(), "\t".to_string())]);
+	}
+	#[test]
+	fn tokenize_delims() {
+		assert_eq!(tokenize("{{=<% %>=}}<%{x}%> {{y}}<%={{ }}=%>{{&z}}", "{{", "}}").unwrap(), vec![
+			Token::SetDelim,
+			Token::Var("x".to_string(), false),
+			Token::Text(" {{y}}".to_string()),
+			Token::SetDelim,
+			Token::Var("z".to_string(), false),
+		]);
+		assert!(tokenize("{{=<%=}}", "{{", "}}").is_err());
+		assert!(tokenize("{{x", "{{", "}}").is_err());
+		assert!(tokenize("{{ }}", "{{", "}}").is_err());
+	}
+	#[test]
+	fn build_tree_errors() {
+		assert!(parse("{{#a}}{{/b}}", "{{", "}}").is_err());
+		assert!(parse("{{#a}}", "{{", "}}").is_err());
+		assert!(parse("{{/a}}", "{{", "}}").is_err());
+	}
+	#[test]
+	fn lookup_stack() {
+		let doc = &::yaml_rust::YamlLoader::load_from_str("a: {b: {c: 1}}\nd: 2\nlist: [x, y]").unwrap()[0];
+		let inner = lookup(&[doc], "a.b").unwrap();
+		assert_eq!(lookup(&[doc, inner], "c"), Some(&Yaml::Integer(1)));
+		assert_eq!(lookup(&[doc, inner], "d"), Some(&Yaml::Integer(2)));
+		assert_eq!(lookup(&[doc, inner], "."), Some(inner));
+		assert_eq!(lookup(&[doc, inner], "a.c"), None);
+		assert_eq!(lookup(&[doc], "list.1"), Some(&Yaml::String("y".to_string())));
+	}
+	#[test]
+	fn escape_basic() {
+		assert_eq!(escape("<a href=\"/x\">Tom & Jerry's</a>"), "&lt;a href&#x3D;&quot;&#x2F;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;&#x2F;a&gt;");
+	}
+}
@@ -1,9 +1,10 @@
 use ::nom;
-use ::yaml_rust;
 use ::yaml_rust::Yaml;
 use ::errors::*;
+use ::format::*;
+use ::expr::*;
 
-const KEYCHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+pub const KEYCHARS: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum YamlPathElem {
@@ -14,6 +15,27 @@ pub enum YamlPathElem {
 
 pub type YamlPath = Vec<YamlPathElem>;
 
+// Where a tag starts in the input file, counting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Pos {
+	pub line: usize,
+	pub col: usize,
+}
+
+// A tag as it was written, for outputting it unchanged
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Source {
+	pub pos: Pos,
+	pub text: String, // Including the delimiters
+	pub end: String, // For sections, the tag that ends them
+}
+
+impl ::std::fmt::Display for Pos {
+	fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+		write!(f, "{}:{}", self.line, self.col)
+	}
+}
+
 /*pub fn path_str(p: &YamlPath) -> String { // TODO Surely this can be done more cleanly (less format!())
 	p.iter().map(|x| match x {
 		YamlPathElem::DownObject(ref s) => s.to_string(),
@@ -23,7 +45,7 @@ pub type YamlPath = Vec<YamlPathElem>;
 	}).fold("".to_string(), |ret, cur| format!("{}.{}", ret, cur)) // FIXME Get rid of first "."
 }*/
 
-named!(yaml_path<&str, Result<YamlPath>>,
+named!(pub yaml_path<&str, Result<YamlPath>>,
 	do_parse!(
 		path: ws!(
 			pair!(
@@ -33,14 +55,14 @@ named!(yaml_path<&str, Result<YamlPath>>,
 					ws!(
 						alt!(
 							do_parse!(name: is_a!(KEYCHARS) >> (Ok(YamlPathElem::Down(name.to_string())))) | // FIXME YAML keys can consist of any character, properly escaped.  So we'll have to be more robust about this.
-							do_parse!(tag_s!("&") >> (Ok(YamlPathElem::Up)))
+							do_parse!(tag!("&") >> (Ok(YamlPathElem::Up)))
 						)
 					)
 				)
 			)
 		) >>
 		({
-			let mut ret: Result<YamlPath> = path.1.into_iter().collect(); // Convert from Vec<Result<YamlPathElem>> to Result<Vec<YamlPathElem>>
+			let ret: Result<YamlPath> = path.1.into_iter().collect(); // Convert from Vec<Result<YamlPathElem>> to Result<Vec<YamlPathElem>>
 			if path.0.is_some() { ret.map(|mut p| { p.insert(0, YamlPathElem::Root); p }) }
 			else { ret }
 		})
@@ -48,83 +70,246 @@ named!(yaml_path<&str, Result<YamlPath>>,
 );
 
 #[derive(Debug, PartialEq)]
-enum Token {
-	Literal(String),
-	DirectSub(YamlPath),
-	CondSub(YamlPath),
-	InvSub(YamlPath),
+pub enum Token {
+	Literal(String, bool), // True for a raw block
+	DirectSub(Expr, Option<Format>),
+	CondSub(Expr, Option<String>, Modifiers),
+	InvSub(Expr, Modifiers),
+	With(YamlPath, String),
+	Let(String, YamlPath),
+	Define(String),
+	Call(String, Option<YamlPath>, Vec<(String, YamlPath)>),
 	EndSub,
 	KeySub(i64),
-	Comment(String),
+	Comment(String, bool), // True for the {{!-- --}} form
+	SetDelim(String, String),
+}
+
+fn set_delim(text: &str) -> Result<Token> {
+	let delims = text.split_whitespace().collect::<Vec<&str>>();
+	match delims.len() {
+		1 => Ok(Token::SetDelim(delims[0].to_string(), ::matching_delim(delims[0]))),
+		2 => Ok(Token::SetDelim(delims[0].to_string(), delims[1].to_string())),
+		_ => Err(Error::from(format!("Delimiter change \"{}\" must contain one or two delimiters", text))),
+	}
 }
 
+named!(alias<&str, String>,
+	do_parse!(tag!("as") >> call!(nom::multispace) >> name: is_a!(KEYCHARS) >> opt!(nom::multispace) >> (name.to_string()))
+);
+
+// Changes to the items a section iterates over, applied in the order listed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Modifiers {
+	pub filter: Option<Expr>, // `where`
+	pub sort: Option<(YamlPath, bool)>, // `sort_by`, with true to sort in descending order
+	pub limit: Option<usize>,
+}
+
+fn check_modifiers(mods: Modifiers) -> Result<Modifiers> { // `.` is the root even here, which is never what's meant for the item
+	let root = vec![YamlPathElem::Root];
+	let mut paths = vec![];
+	if let Some(ref filter) = mods.filter { ::vars::expr_paths(filter, &mut paths); }
+	if paths.contains(&&root) { bail!("`.` in a where clause is the root of the values, not the item; use `as x` and `where x` instead"); }
+	if mods.sort.as_ref().and_then(|s| s.0.first()) == Some(&YamlPathElem::Root) { // Which includes `sort_by . desc`, read as `.desc`
+		bail!("sort_by paths from the root give every item the same key; to sort by the items themselves, use `as x` and `sort_by x`");
+	}
+	Ok(mods)
+}
+
+named!(modifiers<&str, Result<Modifiers>>,
+	do_parse!(
+		filter: opt!(do_parse!(tag!("where") >> call!(nom::multispace) >> e: expr >> (e))) >>
+		sort: opt!(do_parse!(
+			tag!("sort_by") >> call!(nom::multispace) >> path: yaml_path >>
+			order: opt!(terminated!(alt!(tag!("asc") | tag!("desc")), opt!(nom::multispace))) >>
+			(path.map(|p| (p, order == Some("desc"))))
+		)) >>
+		limit: opt!(do_parse!(tag!("limit") >> call!(nom::multispace) >> n: call!(nom::digit) >> opt!(nom::multispace) >> (n))) >>
+		({
+			let limit = limit.map(|n| n.parse::<usize>().chain_err(|| format!("Limit {} is too large", n)));
+			filter.transpose().and_then(|filter| Ok(Modifiers { filter, sort: sort.transpose()?, limit: limit.transpose()? })).and_then(check_modifiers)
+		})
+	)
+);
+
+named!(section_target<&str, Result<Expr>>,
+	alt!( // As in direct substitutions, a plain path is read as a path
+		do_parse!(path: yaml_path >> not!(one_of!("+-*/%<>=!&|(@\"")) >> (path.map(Expr::Path))) |
+		expr
+	)
+);
+
+named!(binding<&str, Result<Token>>,
+	alt!(
+		do_parse!(tag!("with") >> call!(nom::multispace) >> path: yaml_path >> name: alias >> (path.map(|p| Token::With(p, name)))) |
+		do_parse!(target: section_target >> name: opt!(alias) >> mods: modifiers >> (target.and_then(|t| mods.map(|m| Token::CondSub(t, name, m)))))
+	)
+);
+
+named!(let_binding<&str, Result<Token>>,
+	do_parse!(tag!("let") >> call!(nom::multispace) >> name: is_a!(KEYCHARS) >> opt!(nom::multispace) >> char!('=') >> path: yaml_path >> (path.map(|p| Token::Let(name.to_string(), p))))
+);
+
+named!(macro_param<&str, (String, Result<YamlPath>)>,
+	do_parse!(name: is_a!(KEYCHARS) >> opt!(nom::multispace) >> char!('=') >> path: yaml_path >> ((name.to_string(), path)))
+);
+
+named!(macro_tag<&str, Result<Token>>,
+	alt!(
+		do_parse!(tag!("define") >> call!(nom::multispace) >> name: is_a!(KEYCHARS) >> opt!(nom::multispace) >> (Ok(Token::Define(name.to_string())))) |
+		do_parse!(
+			tag!("call") >> call!(nom::multispace) >> name: is_a!(KEYCHARS) >> opt!(nom::multispace) >>
+			context: opt!(terminated!(yaml_path, not!(char!('=')))) >>
+			params: many0!(macro_param) >>
+			({
+				let params = params.into_iter().map(|(k, v)| v.map(|p| (k, p))).collect::<Result<Vec<(String, YamlPath)>>>();
+				match (context, params) {
+					(Some(Err(e)), _) | (_, Err(e)) => Err(e),
+					(Some(Ok(context)), Ok(params)) => Ok(Token::Call(name.to_string(), Some(context), params)),
+					(None, Ok(params)) => Ok(Token::Call(name.to_string(), None, params)),
+				}
+			})
+		)
+	)
+);
+
+named_args!(direct_sub<'a>(close: &str) <&'a str, Result<Token>>,
+	do_parse!(
+		expr: alt!( // A plain path is read as a path even if it looks like a number, as in `{{0}}`
+			do_parse!(path: yaml_path >> peek!(alt!(tag!(":") | tag!(close))) >> (path.map(Expr::Path))) |
+			expr
+		) >>
+		format: opt!(delimited!(char!(':'), format_spec, opt!(nom::multispace))) >>
+		(match (expr, format.transpose()) {
+			(Err(e), _) | (_, Err(e)) => Err(e),
+			(Ok(expr), Ok(format)) => Ok(Token::DirectSub(expr, format)),
+		})
+	)
+);
+
 named_args!(template_sub<'a>(open: &str, close: &str) <&'a str, Result<Token>>,
 	alt!(
-		do_parse!(tag_s!(open) >> tag_s!("!--") >> text: take_until!(&format!("--{}", close)[..]) >> tag_s!("--") >> tag_s!(close) >> (Ok(Token::Comment(text.to_string())))) |
+		do_parse!(tag!(open) >> tag!("!--") >> text: take_until!(&format!("--{}", close)[..]) >> tag!("--") >> tag!(close) >> (Ok(Token::Comment(text.to_string(), true)))) |
+		do_parse!(tag!(open) >> char!('=') >> text: take_until!(&format!("={}", close)[..]) >> char!('=') >> tag!(close) >> (set_delim(text))) |
+		do_parse!(tag!(open) >> tag!("%raw") >> tag!(close) >> text: take_until!(&format!("{}%endraw{}", open, close)[..]) >> tag!(open) >> tag!("%endraw") >> tag!(close) >> (Ok(Token::Literal(text.to_string(), true)))) |
 		delimited!(
-			tag_s!(open),
-			switch!(opt!(one_of!("#/^!?")),
-				None => do_parse!(path: yaml_path >> (path.map(|p| Token::DirectSub(p)))) |
-				Some('#') => do_parse!(path: yaml_path >> (path.map(|p| Token::CondSub(p)))) |
-				Some('^') => do_parse!(path: yaml_path >> (path.map(|p| Token::InvSub(p)))) |
+			tag!(open),
+			switch!(opt!(one_of!("#/^!?%*")),
+				None => alt!(let_binding | call!(direct_sub, close)) |
+				Some('#') => call!(binding) |
+				Some('^') => do_parse!(target: section_target >> mods: modifiers >> (target.and_then(|t| mods.map(|m| Token::InvSub(t, m))))) |
 				Some('/') => do_parse!((Ok(Token::EndSub))) | // TODO How do I return Token::EndSub without this pointless do_parse?
-				Some('?') => do_parse!(n: opt!(nom::digit) >> (n.map(|x| x.parse::<i64>().chain_err(|| "Failed to parse digits as number")).unwrap_or(Ok(0)).map(|x| Token::KeySub(x)))) |
-				Some('!') => do_parse!(text: take_until!(close) >> (Ok(Token::Comment(text.to_string()))))
+				Some('?') => do_parse!(n: opt!(nom::digit) >> (n.map(|x| x.parse::<i64>().chain_err(|| "Failed to parse digits as number")).unwrap_or(Ok(0)).map(Token::KeySub))) |
+				Some('!') => do_parse!(text: take_until!(close) >> (Ok(Token::Comment(text.to_string(), false)))) |
+				Some('*') => call!(macro_tag) |
+				Some('%') => alt!(
+					do_parse!(tag!("open") >> (Ok(Token::Literal(open.to_string(), false)))) |
+					do_parse!(tag!("close") >> (Ok(Token::Literal(close.to_string(), false))))
+				)
 			),
-			tag_s!(close)
+			tag!(close)
 		)
 	)
 );
 
 named_args!(template_literal<'a>(open: &str) <&'a str, Result<Token>>,
 	do_parse!(
-		content: alt!(take_until!(open) | nom::rest_s) >> // TODO How do I ensure here that ALL input is processed?
-		(Ok(Token::Literal(content.to_string())))
+		content: alt!(complete!(take_until!(open)) | nom::rest_s) >> // TODO How do I ensure here that ALL input is processed?
+		(Ok(Token::Literal(content.to_string(), false)))
 	)
 );
 
 named!(yaml_block<&str, Option<Result<Vec<Yaml>>>>,
 	opt!(
 		do_parse!(
-			tag_s!("---\n") >>
+			tag!("---\n") >>
 			block: take_until_and_consume!("\n...\n") >>
-			(yaml_rust::YamlLoader::load_from_str(&block).chain_err(|| "Failed to parse YAML block"))
+			(::yaml::load(block, None).chain_err(|| "Failed to parse YAML block"))
 		)
 	)
 );
 
-named_args!(template<'a>(open: &str, close: &str) <&'a str, Vec<Result<Token>>>,
-	many0!(alt!(complete!(call!(template_sub, open, close)) | complete!(call!(template_literal, open))))
+named_args!(template_token<'a>(open: &str, close: &str) <&'a str, Result<Token>>,
+	alt!(complete!(call!(template_sub, open, close)) | complete!(call!(template_literal, open)))
 );
 
+fn template(input: &str, open: &str, close: &str, start: Pos) -> Result<Vec<(Token, Source)>> { // Tokens are read one at a time because a SetDelim changes how the rest of the input is tokenized
+	let (mut open, mut close) = (open.to_string(), close.to_string());
+	let mut ret = vec![];
+	let mut remain = input;
+	let mut pos = start;
+	while ! remain.is_empty() {
+		match template_token(remain, &open, &close) {
+			Ok((s, _)) if s.len() == remain.len() => bail!(format!("Parsing failed at {} at {:?}", pos, remain)),
+			Ok((s, token)) => {
+				let token = token.chain_err(|| format!("Invalid tag at {}", pos))?;
+				if let Token::SetDelim(ref o, ref c) = token {
+					open = o.to_string();
+					close = c.to_string();
+				}
+				let text = &remain[..remain.len() - s.len()];
+				ret.push((token, Source { pos, text: text.to_string(), end: String::new() }));
+				for c in text.chars() {
+					if c == '\n' { pos = Pos { line: pos.line + 1, col: 1 }; }
+					else { pos.col += 1; }
+				}
+				remain = s;
+			},
+			Err(e) => bail!(format!("Parsing failed at {} with {:?}", pos, e)), // FIXME
+		}
+	}
+	Ok(ret)
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Node {
-	Literal(String),
-	DirectSub(YamlPath),
-	CondSub(YamlPath, bool, Vec<Node>), // Second argument false to invert the condition
+	Literal(String, bool), // True for a raw block, which is only kept for printing
+	DirectSub(Expr, Option<Format>, Source),
+	CondSub(Expr, bool, Option<String>, Modifiers, Vec<Node>, Source), // Second argument false to invert the condition, third is the name bound to each item
+	With(YamlPath, String, Vec<Node>, Source),
+	Let(String, YamlPath, Source),
+	Define(String, Vec<Node>),
+	Call(String, Option<YamlPath>, Vec<(String, YamlPath)>, Source), // Second argument is the context to render the macro in, third is the parameters to bind
 	KeySub(i64),
+	Comment(String, bool), // True for the {{!-- --}} form
+	SetDelim(String, String), // Kept so that templates can be printed back with the delimiters they were written with
 }
 
-fn build_tree(tokens: &[Token]) -> (usize, Vec<Node>) {
+fn build_tree(tokens: &[(Token, Source)]) -> (usize, Vec<Node>) {
 	let mut ret = vec![];
 	let mut i: usize = 0;
 	while i < tokens.len() {
-		match tokens[i] {
-			Token::Literal(ref s) => ret.push(Node::Literal(s.to_string())),
-			Token::DirectSub(ref path) => ret.push(Node::DirectSub(path.to_vec())), // TODO Can I do this without all the to_vec()s?
-			Token::CondSub(ref path) => {
+		let source = |end: usize| Source { end: tokens.get(end).map(|t| t.1.text.to_string()).unwrap_or_default(), ..tokens[i].1.clone() }; // Sections closed by the end of the input have no end tag
+		match tokens[i].0 {
+			Token::Literal(ref s, raw) => ret.push(Node::Literal(s.to_string(), raw)),
+			Token::DirectSub(ref expr, ref format) => ret.push(Node::DirectSub(expr.clone(), format.clone(), source(tokens.len()))), // TODO Can I do this without all the to_vec()s?
+			Token::CondSub(ref target, ref name, ref mods) => {
+				let children = build_tree(&tokens[i+1..]);
+				ret.push(Node::CondSub(target.clone(), true, name.clone(), mods.clone(), children.1, source(i + children.0 + 1)));
+				i += children.0 + 1;
+			},
+			Token::InvSub(ref target, ref mods) => { // TODO Decrease duplication between CondSub and InvSub
 				let children = build_tree(&tokens[i+1..]);
-				ret.push(Node::CondSub(path.to_vec(), true, children.1));
+				ret.push(Node::CondSub(target.clone(), false, None, mods.clone(), children.1, source(i + children.0 + 1)));
 				i += children.0 + 1;
 			},
-			Token::InvSub(ref path) => { // TODO Decrease duplication between CondSub and InvSub
+			Token::With(ref path, ref name) => {
 				let children = build_tree(&tokens[i+1..]);
-				ret.push(Node::CondSub(path.to_vec(), false, children.1));
+				ret.push(Node::With(path.to_vec(), name.to_string(), children.1, source(i + children.0 + 1)));
 				i += children.0 + 1;
 			},
+			Token::Let(ref name, ref path) => ret.push(Node::Let(name.to_string(), path.to_vec(), source(tokens.len()))),
+			Token::Define(ref name) => {
+				let children = build_tree(&tokens[i+1..]);
+				ret.push(Node::Define(name.to_string(), children.1));
+				i += children.0 + 1;
+			},
+			Token::Call(ref name, ref context, ref params) => ret.push(Node::Call(name.to_string(), context.clone(), params.to_vec(), source(tokens.len()))),
 			Token::KeySub(n) => ret.push(Node::KeySub(n)),
+			Token::Comment(ref s, long) => ret.push(Node::Comment(s.to_string(), long)),
+			Token::SetDelim(ref open, ref close) => ret.push(Node::SetDelim(open.to_string(), close.to_string())),
 			Token::EndSub => break,
-			_ => (),
 		};
 		i += 1;
 	}
@@ -136,12 +321,13 @@ enum ParsePhase { Start, PostYaml, Done }
 
 pub struct Parser {
 	remain: String,
+	line: usize, // The line of the input that remain starts on
 	state: ParsePhase,
 }
 
 impl Parser {
 	pub fn new(input: &str) -> Self {
-		Parser { remain: input.to_string(), state: ParsePhase::Start }
+		Parser { remain: input.to_string(), line: 1, state: ParsePhase::Start }
 	}
 	pub fn get_yaml(&mut self) -> Result<Option<Vec<Yaml>>> {
 		if self.state != ParsePhase::Start { bail!("YAML has already been retrieved"); }
@@ -149,28 +335,45 @@ impl Parser {
 		match yaml_block(&self.remain.clone()) { // TODO Is this clone necessary?
 			Err(e) => bail!(format!("Parsing failed with {:?}", e)), // FIXME Can't chain_err, probably because Nom's error type is holding on to the input document.  How do I deal with this?
 			Ok((s, None)) => { self.remain = s.to_string(); Ok(None) },
-			Ok((s, Some(x))) => { let ret = x.chain_err(|| "Failed to parse input as YAML")?; self.remain = s.to_string(); Ok(Some(ret)) },
+			Ok((s, Some(x))) => {
+				let ret = x.chain_err(|| "Failed to parse input as YAML")?;
+				self.line += self.remain[..self.remain.len() - s.len()].matches('\n').count();
+				self.remain = s.to_string();
+				Ok(Some(ret))
+			},
 		}
 	}
+	pub fn remaining(&self) -> &str { // The input that hasn't been retrieved yet
+		&self.remain
+	}
+	pub fn get_text(&mut self) -> Result<String> {
+		if self.state == ParsePhase::Start { self.get_yaml()?; }
+		if self.state != ParsePhase::PostYaml { bail!("Template has already been retrieved"); }
+		self.state = ParsePhase::Done;
+		Ok(self.remain.to_string())
+	}
 	pub fn get_tpl(&mut self, open: &str, close: &str) -> Result<Vec<Node>> {
+		Ok(build_tree(&self.get_tokens(open, close)?).1)
+	}
+	pub fn get_tokens(&mut self, open: &str, close: &str) -> Result<Vec<(Token, Source)>> { // The template before sections are matched up with their ends
 		if self.state == ParsePhase::Start { self.get_yaml()?; }
 		if self.state != ParsePhase::PostYaml { bail!("Template has already been retrieved"); }
 		self.state = ParsePhase::Done;
-		match template(&self.remain, open, close) {
-			Err(e) => bail!(format!("Parsing failed with {:?}", e)), // FIXME
-			Ok((s, tokens)) => {
-				let mut ret = tokens.into_iter().collect::<Result<Vec<Token>>>()?;
-				ret.push(Token::Literal(s.to_string()));
-				Ok(build_tree(&ret).1)
-			},
-		}
+		template(&self.remain, open, close, Pos { line: self.line, col: 1 })
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	fn template(input: &str, open: &str, close: &str) -> Result<Vec<Token>> {
+		super::template(input, open, close, Pos { line: 1, col: 1 }).map(|tokens| tokens.into_iter().map(|(token, _)| token).collect())
+	}
+	fn source(line: usize, col: usize, text: &str, end: &str) -> Source {
+		Source { pos: Pos { line, col }, text: text.to_string(), end: end.to_string() }
+	}
 	fn do_test(input: Vec<Token>, expected: Vec<Node>) {
+		let input = input.into_iter().map(|token| (token, Source::default())).collect::<Vec<(Token, Source)>>();
 		assert_eq!(build_tree(&input), (input.len(), expected));
 	}
 	#[test]
@@ -178,13 +381,14 @@ mod tests {
 		do_test(vec![], vec![]);
 		do_test(
 			vec![
-				Token::Literal("a".to_string()),
-				Token::DirectSub(vec![YamlPathElem::Down("b".to_string())]),
-				Token::Comment("ignore me".to_string()),
+				Token::Literal("a".to_string(), false),
+				Token::DirectSub(Expr::Path(vec![YamlPathElem::Down("b".to_string())]), None),
+				Token::Comment("ignore me".to_string(), false),
 			],
 			vec![
-				Node::Literal("a".to_string()),
-				Node::DirectSub(vec![YamlPathElem::Down("b".to_string())]),
+				Node::Literal("a".to_string(), false),
+				Node::DirectSub(Expr::Path(vec![YamlPathElem::Down("b".to_string())]), None, Source::default()),
+				Node::Comment("ignore me".to_string(), false),
 			]
 		);
 	}
@@ -193,21 +397,32 @@ mod tests {
 		let path = vec![YamlPathElem::Down("a".to_string())];
 		do_test(
 			vec![
-				Token::CondSub(path.clone()),
-				Token::Literal("item: ".to_string()),
-				Token::DirectSub(vec![]),
+				Token::CondSub(Expr::Path(path.clone()), None, Modifiers::default()),
+				Token::Literal("item: ".to_string(), false),
+				Token::DirectSub(Expr::Path(vec![]), None),
 				Token::EndSub,
-				Token::InvSub(path.clone()),
-				Token::Literal("missing".to_string()),
+				Token::InvSub(Expr::Path(path.clone()), Modifiers::default()),
+				Token::Literal("missing".to_string(), false),
 				Token::EndSub,
 			],
 			vec![
-				Node::CondSub(path.clone(), true, vec![Node::Literal("item: ".to_string()), Node::DirectSub(vec![])]),
-				Node::CondSub(path.clone(), false, vec![Node::Literal("missing".to_string())]),
+				Node::CondSub(Expr::Path(path.clone()), true, None, Modifiers::default(), vec![Node::Literal("item: ".to_string(), false), Node::DirectSub(Expr::Path(vec![]), None, Source::default())], Source::default()),
+				Node::CondSub(Expr::Path(path.clone()), false, None, Modifiers::default(), vec![Node::Literal("missing".to_string(), false)], Source::default()),
 			]
 		);
 	}
 	#[test]
+	fn build_tree_format() {
+		let format = Some(Format { width: Some(3), ..Format::default() });
+		do_test(
+			vec![Token::DirectSub(Expr::Path(vec![]), format.clone())],
+			vec![Node::DirectSub(Expr::Path(vec![]), format.clone(), Source::default())]
+		);
+		let path = Expr::Path(vec![YamlPathElem::Down("a".to_string())]);
+		assert_eq!(direct_sub("a:3}}", "}}").unwrap().1.unwrap(), Token::DirectSub(path.clone(), format));
+		assert_eq!(direct_sub("a}}", "}}").unwrap().1.unwrap(), Token::DirectSub(path, None));
+	}
+	#[test]
 	fn template_parse() {
 		use super::Parser;
 		let good = vec!["{}{x}", "{#}", "{#&.&.asd35_.__.342.x}", "{!-- }} -- } ((( --}"];
@@ -215,4 +430,114 @@ mod tests {
 		for t in good { assert!(Parser::new(t).get_tpl("{", "}").is_ok()); }
 		for t in bad { assert!(Parser::new(t).get_tpl("{", "}").is_err()); }
 	}
+	#[test]
+	fn template_set_delim() {
+		use self::YamlPathElem::Down;
+		assert_eq!(Parser::new("{{x}}{{=<% %>=}}<%y%>{{z}}<%= [ =%>[! c ][w]").get_tpl("{{", "}}").unwrap(), vec![
+			Node::DirectSub(Expr::Path(vec![Down("x".to_string())]), None, source(1, 1, "{{x}}", "")),
+			Node::SetDelim("<%".to_string(), "%>".to_string()),
+			Node::DirectSub(Expr::Path(vec![Down("y".to_string())]), None, source(1, 17, "<%y%>", "")),
+			Node::Literal("{{z}}".to_string(), false),
+			Node::SetDelim("[".to_string(), "]".to_string()),
+			Node::Comment(" c ".to_string(), false),
+			Node::DirectSub(Expr::Path(vec![Down("w".to_string())]), None, source(1, 42, "[w]", "")),
+		]);
+		assert!(Parser::new("{{==}}").get_tpl("{{", "}}").is_err());
+		assert!(Parser::new("{{=a b c=}}").get_tpl("{{", "}}").is_err());
+	}
+	#[test]
+	fn template_positions() {
+		let mut parser = Parser::new("---\na: 1\n...\nx{{y}}\n  {{#z}}{{/}}{{^z}}");
+		parser.get_yaml().unwrap();
+		assert_eq!(parser.get_tpl("{{", "}}").unwrap(), vec![
+			Node::Literal("x".to_string(), false),
+			Node::DirectSub(Expr::Path(vec![YamlPathElem::Down("y".to_string())]), None, source(4, 2, "{{y}}", "")),
+			Node::Literal("\n  ".to_string(), false),
+			Node::CondSub(Expr::Path(vec![YamlPathElem::Down("z".to_string())]), true, None, Modifiers::default(), vec![], source(5, 3, "{{#z}}", "{{/}}")),
+			Node::CondSub(Expr::Path(vec![YamlPathElem::Down("z".to_string())]), false, None, Modifiers::default(), vec![], source(5, 14, "{{^z}}", "")),
+		]);
+	}
+	#[test]
+	fn template_bindings() {
+		use self::YamlPathElem::*;
+		let path = |s: &str| s.split('.').map(|x| Down(x.to_string())).collect::<YamlPath>();
+		assert_eq!(template("{{#a.b as c}}{{# d as  e }}{{#with .f as g}}{{let h = &.i}}{{let j=k}}{{#as}}{{let}}", "{{", "}}").unwrap(), vec![
+			Token::CondSub(Expr::Path(path("a.b")), Some("c".to_string()), Modifiers::default()),
+			Token::CondSub(Expr::Path(path("d")), Some("e".to_string()), Modifiers::default()),
+			Token::With(vec![Root, Down("f".to_string())], "g".to_string()),
+			Token::Let("h".to_string(), vec![Up, Down("i".to_string())]),
+			Token::Let("j".to_string(), path("k")),
+			Token::CondSub(Expr::Path(path("as")), None, Modifiers::default()),
+			Token::DirectSub(Expr::Path(path("let")), None),
+		]);
+		let bad = vec!["{{#a as}}", "{{#a asb}}", "{{#with a}}", "{{^a as b}}", "{{let a}}", "{{let = a}}", "{{a as b}}"];
+		for t in bad { assert!(template(t, "{{", "}}").is_err()); }
+	}
+	#[test]
+	fn template_macros() {
+		use self::YamlPathElem::*;
+		let path = |s: &str| s.split('.').map(|x| Down(x.to_string())).collect::<YamlPath>();
+		assert_eq!(template("{{*define a}}{{*call a}}{{*call a b.c}}{{*call a .d e = f g=&.h}}{{*call a i=j}}", "{{", "}}").unwrap(), vec![
+			Token::Define("a".to_string()),
+			Token::Call("a".to_string(), Some(vec![]), vec![]),
+			Token::Call("a".to_string(), Some(path("b.c")), vec![]),
+			Token::Call("a".to_string(), Some(vec![Root, Down("d".to_string())]), vec![("e".to_string(), path("f")), ("g".to_string(), vec![Up, Down("h".to_string())])]),
+			Token::Call("a".to_string(), None, vec![("i".to_string(), path("j"))]),
+		]);
+		let bad = vec!["{{*define}}", "{{*define a b}}", "{{*call}}", "{{*call a b c}}", "{{*call a =b}}", "{{*a}}"];
+		for t in bad { assert!(template(t, "{{", "}}").is_err()); }
+	}
+	#[test]
+	fn template_format() {
+		use self::YamlPathElem::*;
+		assert_eq!(template("{{x:>5}}{{ y :,.2f }}{{.z:}}", "{{", "}}").unwrap(), vec![
+			Token::DirectSub(Expr::Path(vec![Down("x".to_string())]), Some(Format { align: Some('>'), width: Some(5), ..Format::default() })),
+			Token::DirectSub(Expr::Path(vec![Down("y".to_string())]), Some(Format { grouping: Some(','), precision: Some(2), kind: Some('f'), ..Format::default() })),
+			Token::DirectSub(Expr::Path(vec![Root, Down("z".to_string())]), Some(Format::default())),
+		]);
+		let bad = vec!["{{x:q}}", "{{x:5.}}", "{{x::}}", "{{#x:5}}"];
+		for t in bad { assert!(template(t, "{{", "}}").is_err()); }
+	}
+	#[test]
+	fn template_expr() {
+		use self::YamlPathElem::*;
+		let bin = |op, a, b| Expr::Binary(op, Box::new(a), Box::new(b));
+		assert_eq!(template("{{0}}{{a-b}}{{a - b:.1f}}{{ 2*x }}", "{{", "}}").unwrap(), vec![
+			Token::DirectSub(Expr::Path(vec![Down("0".to_string())]), None),
+			Token::DirectSub(Expr::Path(vec![Down("a-b".to_string())]), None),
+			Token::DirectSub(bin(Op::Sub, Expr::Path(vec![Down("a".to_string())]), Expr::Path(vec![Down("b".to_string())])), Some(Format { precision: Some(1), kind: Some('f'), ..Format::default() })),
+			Token::DirectSub(bin(Op::Mul, Expr::Int(2), Expr::Path(vec![Down("x".to_string())])), None),
+		]);
+		let bad = vec!["{{x +}}", "{{(x}}", "{{f(x)}}", "{{x * *}}"];
+		for t in bad { assert!(template(t, "{{", "}}").is_err()); }
+	}
+	#[test]
+	fn template_modifiers() {
+		use self::YamlPathElem::*;
+		let path = |s: &str| s.split('.').map(|x| Down(x.to_string())).collect::<YamlPath>();
+		let paid = Expr::Not(Box::new(Expr::Path(path("paid"))));
+		assert_eq!(template("{{#o where !paid}}{{#o as x where !paid sort_by x.v desc limit 5}}{{^o where !paid}}{{#o sort_by v}}{{#o limit 0}}{{#@first}}{{#a - 1}}", "{{", "}}").unwrap(), vec![
+			Token::CondSub(Expr::Path(path("o")), None, Modifiers { filter: Some(paid.clone()), ..Modifiers::default() }),
+			Token::CondSub(Expr::Path(path("o")), Some("x".to_string()), Modifiers { filter: Some(paid.clone()), sort: Some((path("x.v"), true)), limit: Some(5) }),
+			Token::InvSub(Expr::Path(path("o")), Modifiers { filter: Some(paid.clone()), ..Modifiers::default() }),
+			Token::CondSub(Expr::Path(path("o")), None, Modifiers { sort: Some((path("v"), false)), ..Modifiers::default() }),
+			Token::CondSub(Expr::Path(path("o")), None, Modifiers { limit: Some(0), ..Modifiers::default() }),
+			Token::CondSub(Expr::Meta("first".to_string()), None, Modifiers::default()),
+			Token::CondSub(Expr::Binary(Op::Sub, Box::new(Expr::Path(path("a"))), Box::new(Expr::Int(1))), None, Modifiers::default()),
+		]);
+		let bad = vec!["{{#o where}}", "{{#o limit x}}", "{{#o sort_by v limit 1 where x}}", "{{#o where x as y}}", "{{#@nope}}", "{{#o where . > 1}}", "{{#o sort_by . desc}}"];
+		for t in bad { assert!(template(t, "{{", "}}").is_err()); }
+	}
+	#[test]
+	fn template_raw() {
+		assert_eq!(template("a{{%raw}}{{#b}}{{%open}}{{/}}{{%endraw}}c{{%open}}{{%close}}", "{{", "}}").unwrap(), vec![
+			Token::Literal("a".to_string(), false),
+			Token::Literal("{{#b}}{{%open}}{{/}}".to_string(), true),
+			Token::Literal("c".to_string(), false),
+			Token::Literal("{{".to_string(), false),
+			Token::Literal("}}".to_string(), false),
+		]);
+		assert!(template("{{%raw}}{{x}}", "{{", "}}").is_err());
+		assert!(template("{{%other}}", "{{", "}}").is_err());
+	}
 }
@@ -0,0 +1,153 @@
+use ::parse::*;
+use ::expr::{Expr, Op};
+
+// Turns parsed templates back into template source.  Raw blocks and comments are written in the form they were parsed from, and other
+// literal text that contains the opening delimiter has it replaced with `%open`.
+
+pub fn path(path: &YamlPath) -> String {
+	let root = path.first() == Some(&YamlPathElem::Root);
+	let elems = path.iter().filter_map(|elem| match elem {
+		YamlPathElem::Down(ref key) => Some(key.to_string()),
+		YamlPathElem::Up => Some("&".to_string()),
+		YamlPathElem::Root => None,
+	}).collect::<Vec<String>>();
+	format!("{}{}", if root { "." } else { "" }, elems.join("."))
+}
+
+fn op(op: Op) -> (&'static str, u8) { // The operator and its precedence, higher binding tighter
+	match op {
+		Op::Or => ("||", 1),
+		Op::And => ("&&", 2),
+		Op::Eq => ("==", 3), Op::Ne => ("!=", 3), Op::Lt => ("<", 3), Op::Le => ("<=", 3), Op::Gt => (">", 3), Op::Ge => (">=", 3),
+		Op::Add => ("+", 4), Op::Sub => ("-", 4),
+		Op::Mul => ("*", 5), Op::Div => ("/", 5), Op::Rem => ("%", 5),
+	}
+}
+
+fn precedence(expr: &Expr) -> u8 {
+	match expr {
+		Expr::Binary(o, _, _) => op(*o).1,
+		Expr::Not(_) => 6,
+		_ => 7,
+	}
+}
+
+fn operand(e: &Expr, min: u8) -> String { // Parenthesizes expressions that bind more loosely than min
+	if precedence(e) < min { format!("({})", expr(e)) } else { expr(e) }
+}
+
+pub fn expr(e: &Expr) -> String {
+	match e {
+		Expr::Path(ref p) => path(p),
+		Expr::Int(x) => x.to_string(),
+		Expr::Real(ref x) => x.to_string(),
+		Expr::Str(ref s) => format!("\"{}\"", s),
+		Expr::Meta(ref name) => format!("@{}", name),
+		Expr::Not(ref inner) => format!("!{}", operand(inner, 6)),
+		Expr::Binary(o, ref a, ref b) => {
+			let (name, prec) = op(*o);
+			let left = if prec == 3 { prec + 1 } else { prec }; // Comparisons can't be chained
+			format!("{} {} {}", operand(a, left), name, operand(b, prec + 1))
+		},
+		Expr::Call(ref name, ref args) => format!("{}({})", name, args.iter().map(expr).collect::<Vec<String>>().join(", ")),
+	}
+}
+
+fn target(e: &Expr) -> String { // Numbers on their own would be read as paths
+	match e {
+		Expr::Int(_) | Expr::Real(_) => format!("({})", expr(e)),
+		_ => expr(e),
+	}
+}
+
+fn modifiers(mods: &Modifiers) -> String {
+	let mut ret = String::new();
+	if let Some(ref filter) = mods.filter { ret.push_str(&format!(" where {}", expr(filter))); }
+	if let Some((ref key, desc)) = mods.sort { ret.push_str(&format!(" sort_by {}{}", path(key), if desc { " desc" } else { "" })); }
+	if let Some(limit) = mods.limit { ret.push_str(&format!(" limit {}", limit)); }
+	ret
+}
+
+pub fn literal(text: &str, open: &str, close: &str) -> String {
+	text.replace(open, &format!("{}%open{}", open, close))
+}
+
+pub fn delimiters(open: &str, close: &str) -> String { // A delimiter change to put at the start of output using other delimiters
+	if open == "{{" && close == "}}" { String::new() } else { format!("{{{{={} {}=}}}}", open, close) }
+}
+
+pub fn tag(node: &Node, open: &str, close: &str) -> String { // Only the node's own tag, which for sections is the one that starts them
+	let inner = match node {
+		Node::Literal(ref s, true) => return format!("{}%raw{}{}{}%endraw{}", open, close, s, open, close),
+		Node::Literal(ref s, false) => return literal(s, open, close),
+		Node::DirectSub(ref e, ref format, _) => match format {
+			Some(ref format) => format!("{}:{}", target(e), format),
+			None => target(e),
+		},
+		Node::CondSub(ref e, true, ref name, ref mods, _, _) => format!("#{}{}{}", target(e), name.as_ref().map(|n| format!(" as {}", n)).unwrap_or_default(), modifiers(mods)),
+		Node::CondSub(ref e, false, _, ref mods, _, _) => format!("^{}{}", target(e), modifiers(mods)),
+		Node::With(ref p, ref name, ..) => format!("#with {} as {}", path(p), name),
+		Node::Let(ref name, ref p, _) => format!("let {} = {}", name, path(p)),
+		Node::Define(ref name, _) => format!("*define {}", name),
+		Node::Call(ref name, ref context, ref params, _) => {
+			let mut ret = format!("*call {}", name);
+			if let Some(ref context) = context { if ! context.is_empty() { ret.push_str(&format!(" {}", path(context))); } }
+			for (k, v) in params { ret.push_str(&format!(" {}={}", k, path(v))); }
+			ret
+		},
+		Node::KeySub(0) => "?".to_string(),
+		Node::KeySub(n) => format!("?{}", n),
+		Node::Comment(ref s, true) => format!("!--{}--", s),
+		Node::Comment(ref s, false) => format!("!{}", s),
+		Node::SetDelim(ref o, ref c) => format!("={} {}=", o, c),
+	};
+	format!("{}{}{}", open, inner, close)
+}
+
+pub fn end(open: &str, close: &str) -> String {
+	format!("{}/{}", open, close)
+}
+
+fn nodes(tree: &[Node], delims: &mut (String, String), ret: &mut String) { // Delimiter changes last past the end of the section they're in
+	for node in tree {
+		ret.push_str(&tag(node, &delims.0, &delims.1));
+		match node {
+			Node::CondSub(.., ref children, _) | Node::With(_, _, ref children, _) | Node::Define(_, ref children) => {
+				nodes(children, delims, ret);
+				ret.push_str(&end(&delims.0, &delims.1));
+			},
+			Node::SetDelim(ref open, ref close) => *delims = (open.to_string(), close.to_string()),
+			_ => (),
+		}
+	}
+}
+
+pub fn tree(tree: &[Node], open: &str, close: &str) -> String {
+	let mut ret = String::new();
+	nodes(tree, &mut (open.to_string(), close.to_string()), &mut ret);
+	ret
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn roundtrip(tpl: &str, open: &str, close: &str) -> String { // Printing is checked against the expected output, and must be stable
+		let ret = tree(&Parser::new(tpl).get_tpl(open, close).unwrap(), open, close);
+		assert_eq!(tree(&Parser::new(&ret).get_tpl(open, close).unwrap(), open, close), ret);
+		ret
+	}
+	#[test]
+	fn print_tree() {
+		assert_eq!(roundtrip("a{{b}} {{.c.&.d:>5.2f}}{{^e}}{{?}}{{?2}}{{/}}", "{{", "}}"), "a{{b}} {{.c.&.d:>5.2f}}{{^e}}{{?}}{{?2}}{{/}}");
+		assert_eq!(roundtrip("{{#a as x where !x.p && (b || c) sort_by v desc limit 3}}{{x.q}}{{/}}", "{{", "}}"), "{{#a as x where !x.p && (b || c) sort_by v desc limit 3}}{{x.q}}{{/}}");
+		assert_eq!(roundtrip("{{(a-b)*2}}{{ a - (b - c) }}{{(1)}}{{(1.5):x}}{{1.5}}{{#(a < b) == c}}{{/}}{{#!(a || b)}}{{/}}", "{{", "}}"),
+			"{{a-b * 2}}{{a - (b - c)}}{{(1)}}{{(1.5):x}}{{1.5}}{{#(a < b) == c}}{{/}}{{#!(a || b)}}{{/}}");
+		assert_eq!(roundtrip("{{sum(o, \"v\") / count(o)}}{{#@first}}{{/}}{{x:*^+$#012_.3%}}", "{{", "}}"), "{{sum(o, \"v\") / count(o)}}{{#@first}}{{/}}{{x:*^+$#012_.3%}}");
+		assert_eq!(roundtrip("{{#with .a as b}}{{let c = &.d}}{{*define m}}{{*call m}}{{/}}{{/}}{{*call m e.f g=h}}{{*call m i=j}}", "{{", "}}"),
+			"{{#with .a as b}}{{let c = &.d}}{{*define m}}{{*call m}}{{/}}{{/}}{{*call m e.f g=h}}{{*call m i=j}}");
+		assert_eq!(roundtrip("<%x%> {{ <%%open%> <%!c%><%={{ }}=%>{{y}}", "<%", "%>"), "<%x%> {{ <%%open%> <%!c%><%={{ }}=%>{{y}}");
+		assert_eq!(roundtrip("a {{%raw}}{{x}} and {{#y}}{{%endraw}} b{{!-- c --}}{{! d }}{{%open}}x}}", "{{", "}}"), "a {{%raw}}{{x}} and {{#y}}{{%endraw}} b{{!-- c --}}{{! d }}{{%open}}x}}");
+		assert_eq!(roundtrip("{{=[ ]=}}[%raw]{{[x]}}[%endraw][!-- c --]", "{{", "}}"), "{{=[ ]=}}[%raw]{{[x]}}[%endraw][!-- c --]");
+		assert_eq!(roundtrip("{{# a  where  b>1 }}{{=[ ]=}}[! x ][!-- ] --][/][={{ }}=]{{ b:>3 }}", "{{", "}}"), "{{#a where b > 1}}{{=[ ]=}}[! x ][!-- ] --][/][={{ }}=]{{b:>3}}");
+	}
+}
@@ -0,0 +1,91 @@
+use ::std::collections::{BTreeSet, HashSet};
+use ::yaml_rust::Yaml;
+use ::parse::*;
+use ::vars::Usage;
+
+// Records every value looked up while rendering, to find the values that were never used and the substitutions that failed.
+// Substituting or testing a value uses all of it, but iterating over a sequence or mapping only uses its items that are looked up.
+
+#[derive(Debug, Default)]
+pub struct Report {
+	lookups: Vec<(Vec<String>, Pos, Usage, bool)>, // The last element is whether the value was found
+}
+
+fn keys(path: &YamlPath) -> Vec<String> {
+	path.iter().filter_map(|elem| match elem {
+		YamlPathElem::Down(ref key) => Some(key.to_string()),
+		_ => None, // Joined paths only contain keys
+	}).collect()
+}
+
+fn leaves(yaml: &Yaml, path: &mut Vec<String>, ret: &mut Vec<Vec<String>>) { // Scalars and empty collections, in order
+	match yaml {
+		Yaml::Hash(ref h) if ! h.is_empty() => for (k, v) in h {
+			if path.is_empty() && (k.as_str() == Some("_config") || k.as_str() == Some("_schema")) { continue; } // Settings aren't values
+			path.push(::yaml::string(k, true).unwrap_or_default());
+			leaves(v, path, ret);
+			path.pop();
+		},
+		Yaml::Array(ref a) if ! a.is_empty() => for (i, v) in a.iter().enumerate() {
+			path.push(i.to_string());
+			leaves(v, path, ret);
+			path.pop();
+		},
+		_ => ret.push(path.to_vec()),
+	}
+}
+
+impl Report {
+	pub fn record(&mut self, path: &YamlPath, pos: Pos, usage: Usage, found: bool) {
+		self.lookups.push((keys(path), pos, usage, found));
+	}
+	pub fn unused(&self, values: &Yaml) -> Vec<String> {
+		let whole = self.lookups.iter().filter(|l| l.3 && l.2 != Usage::Iterated).map(|l| &l.0[..]).collect::<HashSet<&[String]>>();
+		let seen = self.lookups.iter().filter(|l| l.3).map(|l| &l.0[..]).collect::<HashSet<&[String]>>();
+		let mut all = vec![];
+		leaves(values, &mut vec![], &mut all);
+		all.into_iter().filter(|leaf| ! leaf.is_empty() && ! seen.contains(&leaf[..]) && ! (0..leaf.len()).any(|n| whole.contains(&leaf[..n])))
+			.map(|leaf| leaf.join(".")).collect()
+	}
+	pub fn missing(&self) -> Vec<(Pos, String)> { // Substitutions of values that don't exist, in template order
+		self.lookups.iter().filter(|l| ! l.3 && l.2 == Usage::Substituted).map(|l| (l.1, l.0.join(".")))
+			.collect::<BTreeSet<(Pos, String)>>().into_iter().collect()
+	}
+	pub fn summary(&self, values: &Yaml, file: &str) -> Option<String> {
+		let mut ret = vec![];
+		let unused = self.unused(values);
+		if ! unused.is_empty() { ret.push(format!("Values that were never used:\n  {}", unused.join("\n  "))); }
+		let missing = self.missing();
+		if ! missing.is_empty() {
+			let lines = missing.iter().map(|(pos, path)| format!("{} at {}:{}", path, file, pos)).collect::<Vec<String>>();
+			ret.push(format!("Values that were missing:\n  {}", lines.join("\n  ")));
+		}
+		if ret.is_empty() { None } else { Some(ret.join("\n")) }
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::yaml_rust::YamlLoader;
+	#[test]
+	fn report_basic() {
+		let values = YamlLoader::load_from_str("a: 1\nb: [{x: 1, y: 2}, {x: 3}]\nc: {d: 1, e: []}\nf: 2\n_config: {ignore: true}").unwrap().remove(0);
+		let path = |s: &str| s.split('.').map(|k| YamlPathElem::Down(k.to_string())).collect::<YamlPath>();
+		let at = |line| Pos { line, col: 1 };
+		let mut report = Report::default();
+		report.record(&path("a"), at(1), Usage::Substituted, true);
+		report.record(&path("b"), at(2), Usage::Iterated, true);
+		report.record(&path("b.0.x"), at(3), Usage::Substituted, true);
+		report.record(&path("b.1.x"), at(3), Usage::Substituted, true);
+		report.record(&path("b.1.y"), at(3), Usage::Substituted, false);
+		report.record(&path("b.1.y"), at(3), Usage::Substituted, false);
+		report.record(&path("c"), at(4), Usage::Tested, true);
+		report.record(&path("g"), at(5), Usage::Tested, false);
+		report.record(&path("h"), at(1), Usage::Substituted, false);
+		assert_eq!(report.unused(&values), vec!["b.0.y", "f"]);
+		assert_eq!(report.missing(), vec![(at(1), "h".to_string()), (at(3), "b.1.y".to_string())]);
+		assert_eq!(report.summary(&values, "t.tpl").unwrap(), "Values that were never used:\n  b.0.y\n  f\nValues that were missing:\n  h at t.tpl:1:1\n  b.1.y at t.tpl:3:1");
+		assert_eq!(Report::default().summary(&Yaml::Hash(Default::default()), "t.tpl"), None);
+	}
+}
@@ -0,0 +1,215 @@
+use ::yaml_rust::Yaml;
+use ::yaml_rust::yaml::Hash;
+use ::errors::*;
+
+// Checks values against the `_schema` mapping, which maps paths to the types and values they must have.  Each path is a list of
+// keys separated by periods, where `*` stands for every item of a sequence or mapping.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind { String, Integer, Number, Boolean, Sequence, Mapping }
+
+impl Kind {
+	fn from_yaml(yaml: &Yaml) -> Result<Self> {
+		Ok(match yaml.as_str() {
+			Some("string") => Kind::String,
+			Some("integer") => Kind::Integer,
+			Some("number") => Kind::Number,
+			Some("boolean") => Kind::Boolean,
+			Some("sequence") => Kind::Sequence,
+			Some("mapping") => Kind::Mapping,
+			_ => bail!("Types must be one of string, integer, number, boolean, sequence, or mapping"),
+		})
+	}
+	fn name(self) -> &'static str {
+		match self {
+			Kind::String => "a string",
+			Kind::Integer => "an integer",
+			Kind::Number => "a number",
+			Kind::Boolean => "a boolean",
+			Kind::Sequence => "a sequence",
+			Kind::Mapping => "a mapping",
+		}
+	}
+	fn matches(self, yaml: &Yaml) -> bool {
+		matches!((self, yaml),
+			(Kind::String, Yaml::String(_)) | (Kind::Integer, Yaml::Integer(_)) | (Kind::Boolean, Yaml::Boolean(_)) |
+			(Kind::Number, Yaml::Integer(_)) | (Kind::Number, Yaml::Real(_)) |
+			(Kind::Sequence, Yaml::Array(_)) | (Kind::Mapping, Yaml::Hash(_))
+		)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+	path: Vec<String>,
+	kind: Option<Kind>,
+	required: bool,
+	default: Option<Yaml>,
+	allowed: Option<Vec<Yaml>>,
+}
+
+impl Rule {
+	fn from_yaml(path: &str, spec: &Yaml) -> Result<Self> {
+		let mut ret = Rule { path: path.split('.').map(|s| s.to_string()).collect(), kind: None, required: true, default: None, allowed: None };
+		match spec {
+			Yaml::String(_) => ret.kind = Some(Kind::from_yaml(spec)?), // A type on its own
+			Yaml::Hash(h) => {
+				let mut required = None;
+				for (k, v) in h {
+					match (k.as_str(), v) {
+						(Some("type"), _) => ret.kind = Some(Kind::from_yaml(v)?),
+						(Some("required"), Yaml::Boolean(b)) => required = Some(*b),
+						(Some("default"), _) => ret.default = Some(v.clone()),
+						(Some("enum"), Yaml::Array(a)) => ret.allowed = Some(a.clone()),
+						(Some("required"), _) => bail!("required must be a boolean"),
+						(Some("enum"), _) => bail!("enum must be a sequence"),
+						_ => bail!(format!("Unknown schema key {:?}", k)),
+					}
+				}
+				ret.required = required.unwrap_or(ret.default.is_none()); // Paths with defaults are optional unless stated otherwise
+			},
+			_ => bail!("Schema entries must be a type or a mapping"),
+		}
+		if ret.path.iter().any(|k| k.is_empty()) { bail!("Schema paths can't have empty keys"); }
+		Ok(ret)
+	}
+	fn check(&self, node: &mut Yaml, depth: usize, at: &[String], errors: &mut Vec<String>) {
+		let key = &self.path[depth];
+		let here = |k: &str| at.iter().map(|s| &s[..]).chain(::std::iter::once(k)).collect::<Vec<&str>>().join(".");
+		let missing = || format!("{} is required", at.iter().chain(self.path[depth..].iter()).cloned().collect::<Vec<String>>().join("."));
+		let container = || format!("{} must be a sequence or mapping", if at.is_empty() { "The top level".to_string() } else { at.join(".") });
+		let last = depth + 1 == self.path.len();
+		if key == "*" {
+			let items: Vec<(String, &mut Yaml)> = match node {
+				Yaml::Hash(h) => h.iter_mut().map(|(k, v)| (k.as_str().unwrap_or("?").to_string(), v)).collect(),
+				Yaml::Array(a) => a.iter_mut().enumerate().map(|(i, v)| (i.to_string(), v)).collect(),
+				_ => { errors.push(container()); return; },
+			};
+			for (k, v) in items {
+				let mut inner = at.to_vec();
+				inner.push(k);
+				if last { self.check_value(v, &inner.join("."), errors); }
+				else { self.check(v, depth + 1, &inner, errors); }
+			}
+			return;
+		}
+		let child = match node {
+			Yaml::Hash(h) => {
+				let k = Yaml::String(key.to_string());
+				if ! h.contains_key(&k) {
+					match self.default {
+						Some(ref default) if last => { h.insert(k.clone(), default.clone()); },
+						Some(_) if ! self.path[depth + 1..].contains(&"*".to_string()) => { h.insert(k.clone(), Yaml::Hash(Hash::new())); },
+						_ => {
+							if self.required { errors.push(missing()); }
+							return;
+						},
+					}
+				}
+				h.get_mut(&k)
+			},
+			Yaml::Array(a) => key.parse::<usize>().ok().and_then(move |i| a.get_mut(i)),
+			_ => { errors.push(container()); return; },
+		};
+		match child {
+			Some(v) if last => self.check_value(v, &here(key), errors),
+			Some(v) => {
+				let mut inner = at.to_vec();
+				inner.push(key.to_string());
+				self.check(v, depth + 1, &inner, errors);
+			},
+			None => if self.required { errors.push(missing()); },
+		}
+	}
+	fn check_value(&self, value: &Yaml, at: &str, errors: &mut Vec<String>) {
+		if let Some(kind) = self.kind {
+			if ! kind.matches(value) { errors.push(format!("{} must be {}", at, kind.name())); }
+		}
+		if let Some(ref allowed) = self.allowed {
+			if ! allowed.contains(value) {
+				let names = allowed.iter().map(|a| ::yaml::string(a, true).unwrap_or_default()).collect::<Vec<String>>();
+				errors.push(format!("{} must be one of {}", at, names.join(", ")));
+			}
+		}
+	}
+}
+
+pub fn defaults(values: &mut Yaml) -> Result<Vec<String>> { // Fills in defaults, and lists every value that doesn't match the schema
+	let rules = match values["_schema"] {
+		Yaml::Hash(ref h) => h.iter().map(|(k, v)| match k.as_str() {
+			Some(path) => Rule::from_yaml(path, v).chain_err(|| format!("Invalid schema for {}", path)),
+			None => Err(Error::from("Schema paths must be strings")),
+		}).collect::<Result<Vec<Rule>>>()?,
+		Yaml::BadValue => return Ok(vec![]),
+		_ => bail!("_schema must be a mapping"),
+	};
+	let mut errors = vec![];
+	for rule in rules.iter() { rule.check(values, 0, &[], &mut errors); }
+	Ok(errors)
+}
+
+pub fn validate(values: &mut Yaml) -> Result<()> { // Fills in defaults and reports every value that doesn't match the schema
+	let errors = defaults(values)?;
+	if ! errors.is_empty() { bail!(format!("Values don't match the schema:\n  {}", errors.join("\n  "))); }
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use ::yaml_rust::YamlLoader;
+	fn load(s: &str) -> Yaml {
+		YamlLoader::load_from_str(s).unwrap().remove(0)
+	}
+	#[test]
+	fn schema_valid() {
+		let mut values = load("
+_schema:
+  name: string
+  env: {type: string, enum: [dev, prod], default: dev}
+  replicas: {type: integer, default: 1}
+  db.port: {default: 5432}
+  hosts: sequence
+  hosts.*.name: string
+  hosts.*.weight: {type: number, default: 1}
+  opt: {type: string, required: false}
+name: app
+hosts: [{name: a}, {name: b, weight: 2.5}]
+");
+		validate(&mut values).unwrap();
+		assert_eq!(values["env"].as_str(), Some("dev"));
+		assert_eq!(values["replicas"].as_i64(), Some(1));
+		assert_eq!(values["db"]["port"].as_i64(), Some(5432));
+		assert_eq!(values["hosts"][0]["weight"].as_i64(), Some(1));
+		assert_eq!(values["hosts"][1]["weight"].as_f64(), Some(2.5));
+		assert!(values["opt"].is_badvalue());
+		let mut plain = load("x: 1");
+		validate(&mut plain).unwrap();
+	}
+	#[test]
+	fn schema_violations() {
+		let mut values = load("
+_schema:
+  name: string
+  env: {enum: [dev, prod]}
+  port: integer
+  hosts.*.name: string
+  a.b.c: boolean
+env: test
+port: '80'
+hosts: [{name: a}, {}, {name: 3}]
+a: {b: 1}
+");
+		let err = validate(&mut values).unwrap_err().to_string();
+		assert_eq!(err, "Values don't match the schema:
+  name is required
+  env must be one of dev, prod
+  port must be an integer
+  hosts.1.name is required
+  hosts.2.name must be a string
+  a.b must be a sequence or mapping");
+		assert!(validate(&mut load("_schema: {x: text}")).is_err());
+		assert!(validate(&mut load("_schema: {x: {type: string, size: 3}}")).is_err());
+		assert!(validate(&mut load("_schema: [x]")).is_err());
+	}
+}
@@ -0,0 +1,39 @@
+use ::yaml_rust;
+use ::mustache;
+
+// Runs the official Mustache spec (test/spec, vendored from https://github.com/mustache/spec) against the mustache dialect.  The
+// optional lambda module is not supported and so is not included.
+
+fn run_spec(name: &str) {
+	let spec = yaml_rust::YamlLoader::load_from_str(&::read_file(&format!("test/spec/{}.yml", name)).unwrap()).unwrap().into_iter().next().unwrap();
+	let tests = spec["tests"].as_vec().unwrap();
+	assert!(! tests.is_empty());
+	let failures = tests.iter().filter_map(|test| {
+		let partials = |name: &str| Ok(test["partials"][name].as_str().map(|s| s.to_string()));
+		let result = mustache::render(test["template"].as_str().unwrap(), &test["data"], "{{", "}}", &partials);
+		match result {
+			Ok(ref s) if Some(&s[..]) == test["expected"].as_str() => None,
+			_ => Some(format!("{}: {}\n  expected {:?}\n  got {:?}", name, test["name"].as_str().unwrap(), test["expected"].as_str().unwrap(), result)),
+		}
+	}).collect::<Vec<String>>();
+	assert!(failures.is_empty(), "\n{}\n", failures.join("\n"));
+}
+
+#[test]
+fn spec_comments() { run_spec("comments"); }
+
+#[test]
+fn spec_delimiters() { run_spec("delimiters"); }
+
+#[test]
+fn spec_interpolation() { run_spec("interpolation"); }
+
+#[test]
+fn spec_inverted() { run_spec("inverted"); }
+
+#[test]
+fn spec_partials() { run_spec("partials"); }
+
+#[test]
+fn spec_sections() { run_spec("sections"); }
+
@@ -0,0 +1,51 @@
+use ::yaml_rust::Yaml;
+use ::parse::*;
+
+// Records the steps rendering takes for --trace: every path looked up along with where it led and what was there, and what each
+// section did.  Steps are kept in the order they happened, so sections that are iterated show their contents once per item.
+
+#[derive(Debug, Default)]
+pub struct Trace {
+	steps: Vec<(Pos, String)>,
+}
+
+fn describe(value: &Yaml) -> String {
+	match value {
+		Yaml::BadValue => "missing".to_string(),
+		Yaml::Array(ref a) => format!("a sequence of {} items", a.len()),
+		Yaml::Hash(ref h) => format!("a mapping of {} keys", h.len()),
+		Yaml::String(ref s) => format!("{:?}", s), // Quoted to tell them apart from numbers and booleans
+		_ => ::yaml::string(value, true).unwrap_or_default(),
+	}
+}
+
+fn path(path: &YamlPath) -> String {
+	if path.is_empty() { ".".to_string() } else { ::print::path(path) }
+}
+
+impl Trace {
+	pub fn lookup(&mut self, pos: Pos, relative: &YamlPath, absolute: &YamlPath, value: &Yaml) {
+		self.steps.push((pos, format!("{} -> {} = {}", path(relative), path(absolute), describe(value))));
+	}
+	pub fn section(&mut self, pos: Pos, tag: &str, outcome: &str) {
+		self.steps.push((pos, format!("{} {}", tag, outcome)));
+	}
+	pub fn lines(&self, file: &str) -> String {
+		self.steps.iter().map(|(pos, step)| format!("{}:{}: {}\n", file, pos, step)).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	#[test]
+	fn trace_lines() {
+		let path = |s: &str| s.split('.').map(|k| YamlPathElem::Down(k.to_string())).collect::<YamlPath>();
+		let mut trace = Trace::default();
+		trace.lookup(Pos { line: 2, col: 3 }, &path("b"), &path("a.0.b"), &Yaml::String("1".to_string()));
+		trace.lookup(Pos { line: 2, col: 9 }, &vec![], &vec![], &Yaml::Array(vec![Yaml::Integer(1)]));
+		trace.lookup(Pos { line: 3, col: 1 }, &vec![YamlPathElem::Root, YamlPathElem::Down("c".to_string())], &path("c"), &Yaml::BadValue);
+		trace.section(Pos { line: 3, col: 1 }, "{{^c}}", "taken");
+		assert_eq!(trace.lines("t.tpl"), "t.tpl:2:3: b -> a.0.b = \"1\"\nt.tpl:2:9: . -> . = a sequence of 1 items\nt.tpl:3:1: .c -> c = missing\nt.tpl:3:1: {{^c}} taken\n");
+	}
+}
@@ -0,0 +1,182 @@
+use ::std::collections::{BTreeMap, BTreeSet};
+use ::yaml_rust::{Yaml, YamlEmitter};
+use ::yaml_rust::yaml::Hash;
+use ::parse::*;
+use ::expr::Expr;
+use ::errors::*;
+
+// Finds every value path a template refers to without rendering it.  Since the values aren't known, the items of a section are
+// represented by the key `*`, so `{{#hosts}}{{name}}{{/}}` uses `hosts` and `hosts.*.name`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Usage { Substituted, Tested, Iterated }
+
+impl Usage {
+	fn name(self) -> &'static str {
+		match self {
+			Usage::Substituted => "substituted",
+			Usage::Tested => "tested",
+			Usage::Iterated => "iterated",
+		}
+	}
+}
+
+pub type Vars = BTreeMap<Vec<String>, BTreeSet<Usage>>;
+
+pub const ITEM: &str = "*";
+
+#[derive(Clone, Default)]
+struct Scope<'a> {
+	aliases: BTreeMap<String, YamlPath>,
+	macros: BTreeMap<String, &'a [Node]>,
+	calls: Vec<String>, // Macros being expanded, so that recursive ones are only followed once
+}
+
+impl<'a> Scope<'a> {
+	fn resolve(&self, context: &YamlPath, path: &YamlPath) -> YamlPath { // The same as resolving paths while rendering
+		if let Some(YamlPathElem::Down(ref name)) = path.first() {
+			if let Some(bound) = self.aliases.get(name) { return ::yaml::pathjoin(&[bound, &path[1..].to_vec()]); }
+		}
+		::yaml::pathjoin(&[context, path])
+	}
+}
+
+pub fn expr_paths<'e>(expr: &'e Expr, ret: &mut Vec<&'e YamlPath>) {
+	match expr {
+		Expr::Path(ref path) => ret.push(path),
+		Expr::Not(ref inner) => expr_paths(inner, ret),
+		Expr::Binary(_, ref a, ref b) => { expr_paths(a, ret); expr_paths(b, ret); },
+		Expr::Call(_, ref args) => for arg in args { expr_paths(arg, ret); },
+		Expr::Int(_) | Expr::Real(_) | Expr::Str(_) | Expr::Meta(_) => (),
+	}
+}
+
+fn record(vars: &mut Vars, path: &YamlPath, usage: Usage) {
+	let keys = path.iter().filter_map(|elem| match elem {
+		YamlPathElem::Down(ref key) => Some(key.to_string()),
+		_ => None, // Joined paths only contain keys
+	}).collect();
+	vars.entry(keys).or_default().insert(usage);
+}
+
+fn walk<'a>(tree: &'a [Node], context: &YamlPath, scope: &Scope<'a>, vars: &mut Vars) {
+	let mut scope = scope.clone();
+	for node in tree {
+		if let Node::Define(ref name, ref body) = node { scope.macros.insert(name.to_string(), body); }
+	}
+	let item = |path: &YamlPath| ::yaml::pathjoin(&[path, &vec![YamlPathElem::Down(ITEM.to_string())]]);
+	for node in tree {
+		match node {
+			Node::Literal(..) | Node::Define(..) | Node::KeySub(_) | Node::Comment(..) | Node::SetDelim(..) => (),
+			Node::DirectSub(ref expr, _, _) => {
+				let mut paths = vec![];
+				expr_paths(expr, &mut paths);
+				for path in paths { record(vars, &scope.resolve(context, path), Usage::Substituted); }
+			},
+			Node::CondSub(Expr::Path(ref path), direct, ref name, ref mods, ref children, _) => {
+				let abspath = scope.resolve(context, path);
+				record(vars, &abspath, if *direct { Usage::Iterated } else { Usage::Tested });
+				let child = item(&abspath);
+				let mut inner = scope.clone();
+				if let Some(ref name) = name { inner.aliases.insert(name.to_string(), child.clone()); }
+				let mut tested = vec![];
+				if let Some(ref filter) = mods.filter { expr_paths(filter, &mut tested); }
+				if let Some((ref key, _)) = mods.sort { tested.push(key); }
+				for path in tested { record(vars, &inner.resolve(&child, path), Usage::Tested); }
+				if *direct { walk(children, &child, &inner, vars); }
+				else { walk(children, &abspath, &scope, vars); }
+			},
+			Node::CondSub(ref expr, _, _, _, ref children, _) => {
+				let mut paths = vec![];
+				expr_paths(expr, &mut paths);
+				for path in paths { record(vars, &scope.resolve(context, path), Usage::Tested); }
+				walk(children, context, &scope, vars);
+			},
+			Node::With(ref path, ref name, ref children, _) => {
+				let mut inner = scope.clone();
+				inner.aliases.insert(name.to_string(), scope.resolve(context, path));
+				walk(children, context, &inner, vars);
+			},
+			Node::Let(ref name, ref path, _) => {
+				let bound = scope.resolve(context, path);
+				scope.aliases.insert(name.to_string(), bound);
+			},
+			Node::Call(ref name, ref path, ref params, _) => {
+				let body = match scope.macros.get(name) {
+					Some(body) if ! scope.calls.contains(name) => body,
+					_ => continue, // Undefined macros are reported when rendering
+				};
+				let mut inner = Scope {
+					aliases: params.iter().map(|(k, v)| (k.to_string(), scope.resolve(context, v))).collect(),
+					macros: scope.macros.clone(),
+					calls: scope.calls.clone(),
+				};
+				inner.calls.push(name.to_string());
+				let context = path.as_ref().map(|p| scope.resolve(context, p)).unwrap_or_else(|| context.to_vec());
+				walk(body, &context, &inner, vars);
+			},
+		}
+	}
+}
+
+pub fn collect(tree: &[Node]) -> Vars {
+	let mut ret = Vars::new();
+	walk(tree, &vec![], &Scope::default(), &mut ret);
+	ret
+}
+
+pub fn list(vars: &Vars) -> String { // One line per path, with the ways it's used
+	vars.iter().map(|(path, usage)| {
+		let path = if path.is_empty() { ".".to_string() } else { path.join(".") };
+		format!("{} ({})\n", path, usage.iter().map(|u| u.name()).collect::<Vec<&str>>().join(", "))
+	}).collect()
+}
+
+fn insert(node: &mut Yaml, path: &[String], usage: &BTreeSet<Usage>) {
+	let (key, rest) = match path.split_first() {
+		Some(split) => split,
+		None => {
+			if let Yaml::Null = node { if usage.contains(&Usage::Iterated) { *node = Yaml::Array(vec![]); } }
+			return;
+		},
+	};
+	if key == ITEM {
+		if let Yaml::Array(ref a) = node { if a.is_empty() { *node = Yaml::Null; } }
+		if ! matches!(node, Yaml::Array(_)) { *node = Yaml::Array(vec![Yaml::Null]); }
+		if let Yaml::Array(ref mut a) = node { insert(&mut a[0], rest, usage); }
+	}
+	else {
+		if ! matches!(node, Yaml::Hash(_)) { *node = Yaml::Hash(Hash::new()); }
+		if let Yaml::Hash(ref mut h) = node { insert(h.entry(Yaml::String(key.to_string())).or_insert(Yaml::Null), rest, usage); }
+	}
+}
+
+pub fn skeleton(vars: &Vars) -> Result<String> { // A values file with every path the template uses, set to null
+	let mut root = Yaml::Hash(Hash::new());
+	for (path, usage) in vars.iter().filter(|(path, _)| ! path.is_empty()) { insert(&mut root, path, usage); }
+	let mut ret = String::new();
+	YamlEmitter::new(&mut ret).dump(&root).chain_err(|| "Failed to write YAML")?;
+	ret.push('\n');
+	Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	fn vars(tpl: &str) -> String {
+		list(&collect(&Parser::new(tpl).get_tpl("{{", "}}").unwrap()))
+	}
+	#[test]
+	fn vars_list() {
+		assert_eq!(vars("{{a}}{{#b}}{{c}}{{.d}}{{^e}}{{/}}{{/}}"), "a (substituted)\nb (iterated)\nb.*.c (substituted)\nb.*.e (tested)\nd (substituted)\n");
+		assert_eq!(vars("{{#a as x where y > 1 sort_by z}}{{x.w}}{{?}}{{&.q}}{{/}}"), "a (iterated)\na.*.w (substituted)\na.*.y (tested)\na.*.z (tested)\na.q (substituted)\n");
+		assert_eq!(vars("{{#a}}{{#a}}{{/}}{{/}}{{#a && !b}}{{a + c}}{{/}}"), "a (substituted, tested, iterated)\na.*.a (iterated)\nb (tested)\nc (substituted)\n");
+		assert_eq!(vars("{{#with x.y as z}}{{z.w}}{{/}}{{let q = x}}{{q.r}}"), "x.r (substituted)\nx.y.w (substituted)\n");
+		assert_eq!(vars("{{*call m x}}{{*define m}}{{y}}{{*call m x}}{{/}}"), "x.y (substituted)\n");
+	}
+	#[test]
+	fn vars_skeleton() {
+		let tree = Parser::new("{{a.b}}{{#c}}{{d}}{{#e}}{{}}{{/}}{{/}}{{#f}}{{/}}").get_tpl("{{", "}}").unwrap();
+		assert_eq!(skeleton(&collect(&tree)).unwrap(), "---\na:\n  b: ~\nc:\n  - d: ~\n    e:\n      - ~\nf: []\n");
+	}
+}
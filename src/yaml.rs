@@ -1,10 +1,11 @@
 use ::yaml_rust::Yaml;
-use ::std::collections::BTreeMap;
-use ::std::collections::btree_map::Entry;
+use ::yaml_rust::yaml::Hash;
+use ::yaml_rust::YamlLoader;
+use ::std::cmp::Ordering;
 use ::parse::*;
 use ::errors::*;
 
-pub fn pathjoin<'a>(paths: &[&YamlPath]) -> YamlPath {
+pub fn pathjoin(paths: &[&YamlPath]) -> YamlPath {
 	let mut ret = vec![];
 	for elem in paths.iter().flat_map(|x| x.iter()) {
 		match elem {
@@ -45,6 +46,35 @@ pub fn bool(yaml: &Yaml) -> bool {
 	}
 }
 
+fn number(yaml: &Yaml) -> Option<f64> {
+	match yaml {
+		Yaml::Integer(x) => Some(*x as f64),
+		Yaml::Real(ref x) => x.parse::<f64>().ok(),
+		_ => None,
+	}
+}
+
+pub fn compare(a: &Yaml, b: &Yaml) -> Option<Ordering> { // Only numbers, strings, and booleans can be ordered, and only against their own kind
+	match (a, b) {
+		(Yaml::Integer(x), Yaml::Integer(y)) => Some(x.cmp(y)),
+		(Yaml::Integer(_), Yaml::Real(_)) | (Yaml::Real(_), Yaml::Integer(_)) | (Yaml::Real(_), Yaml::Real(_)) => number(a)?.partial_cmp(&number(b)?),
+		(Yaml::String(x), Yaml::String(y)) => Some(x.cmp(y)),
+		(Yaml::Boolean(x), Yaml::Boolean(y)) => Some(x.cmp(y)),
+		_ => None,
+	}
+}
+
+pub fn sort_order(a: &Yaml, b: &Yaml, desc: bool) -> Ordering { // Numbers, then strings, then booleans, all reversed if desc, then everything else
+	let rank = |yaml: &Yaml| match yaml {
+		Yaml::Integer(_) | Yaml::Real(_) => 0,
+		Yaml::String(_) => 1,
+		Yaml::Boolean(_) => 2,
+		_ => 3,
+	};
+	let ord = rank(a).cmp(&rank(b)).then_with(|| compare(a, b).unwrap_or(Ordering::Equal));
+	if desc && rank(a) < 3 && rank(b) < 3 { ord.reverse() } else { ord }
+}
+
 pub fn string(yaml: &Yaml, ignore: bool) -> Result<String> {
 	match yaml {
 		Yaml::Real(x) => Ok(x.to_string()),
@@ -56,45 +86,143 @@ pub fn string(yaml: &Yaml, ignore: bool) -> Result<String> {
 	}
 }
 
-pub fn merge(yamls: Vec<Yaml>) -> Yaml {
-	fn recursive_merge(acc: &mut Yaml, cur: &Yaml) { // TODO Is there a way to do this without all the clones?  We should take ownership of cur so we can butcher it for its pieces.
-		match (acc, cur) {
-			(Yaml::Array(orig), Yaml::Array(new)) => orig.extend(new.iter().cloned()),
-			(Yaml::Hash(orig), Yaml::Hash(new)) =>
-				for (k, v) in new.iter() {
-					match orig.entry(k.clone()) {
-						Entry::Vacant(entry) => { entry.insert(v.clone()); },
-						Entry::Occupied(mut entry) => { recursive_merge(entry.get_mut(), v); },
-					}
+pub fn expand_merge_keys(yaml: Yaml) -> Result<Yaml> { // Replaces `<<` keys with the contents of the mappings they refer to
+	let merge_key = Yaml::String("<<".to_string());
+	match yaml {
+		Yaml::Array(a) => a.into_iter().map(expand_merge_keys).collect::<Result<Vec<Yaml>>>().map(Yaml::Array),
+		Yaml::Hash(h) => {
+			let explicit = h.keys().filter(|k| **k != merge_key).cloned().collect::<Vec<Yaml>>();
+			let mut ret = Hash::new();
+			for (k, v) in h {
+				if k != merge_key { ret.insert(k, expand_merge_keys(v)?); continue; }
+				let sources = match expand_merge_keys(v)? {
+					Yaml::Hash(source) => vec![source],
+					Yaml::Array(a) => a.into_iter().map(|source| match source {
+						Yaml::Hash(source) => Ok(source),
+						_ => Err(Error::from("Merge keys can only refer to mappings")),
+					}).collect::<Result<Vec<Hash>>>()?,
+					_ => bail!("Merge keys can only refer to mappings"),
+				};
+				for (sk, sv) in sources.into_iter().flatten() { // Keys given explicitly, and then keys from earlier mappings, take precedence
+					if ! explicit.contains(&sk) && ! ret.contains_key(&sk) { ret.insert(sk, sv); }
+				}
+			}
+			Ok(Yaml::Hash(ret))
+		},
+		other => Ok(other),
+	}
+}
+
+pub fn load(text: &str, document: Option<usize>) -> Result<Vec<Yaml>> { // Returns all of the documents in the text, or only the selected one
+	let docs = YamlLoader::load_from_str(text).chain_err(|| "Failed to parse YAML")?.into_iter().map(expand_merge_keys).collect::<Result<Vec<Yaml>>>()?;
+	match document {
+		Some(n) => {
+			let count = docs.len();
+			docs.into_iter().nth(n).map(|doc| vec![doc]).ok_or_else(|| Error::from(format!("Document {} was requested, but there are only {}", n, count)))
+		},
+		None => Ok(docs),
+	}
+}
+
+// How a value from a later file is combined with the same key's value from earlier ones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy { Append, Replace, Prepend, Unique, Delete }
+
+impl Strategy {
+	fn from_yaml(yaml: &Yaml) -> Result<Self> {
+		Ok(match yaml.as_str() {
+			Some("append") => Strategy::Append,
+			Some("replace") => Strategy::Replace,
+			Some("prepend") => Strategy::Prepend,
+			Some("unique") => Strategy::Unique,
+			Some("delete") => Strategy::Delete,
+			_ => bail!("Merge strategies must be one of append, replace, prepend, unique, or delete"),
+		})
+	}
+}
+
+fn strip(yaml: &Yaml) -> Yaml { // Copies a value, removing the `_merge` keys that only matter while merging
+	let key = Yaml::String("_merge".to_string());
+	match yaml {
+		Yaml::Hash(h) => Yaml::Hash(h.iter().filter(|(k, _)| **k != key).map(|(k, v)| (k.clone(), strip(v))).collect()),
+		Yaml::Array(a) => Yaml::Array(a.iter().map(strip).collect()),
+		_ => yaml.clone(),
+	}
+}
+
+fn recursive_merge(acc: &mut Yaml, cur: &Yaml, strategy: Option<Strategy>, default: Strategy) -> Result<()> { // TODO Is there a way to do this without all the clones?  We should take ownership of cur so we can butcher it for its pieces.
+	match (acc, cur, strategy.unwrap_or(default)) { // Mappings are merged key by key unless replace is given for their key, whatever the default
+		(Yaml::Array(orig), Yaml::Array(new), Strategy::Prepend) => { orig.splice(0..0, new.iter().map(strip)); },
+		(Yaml::Array(orig), Yaml::Array(new), Strategy::Unique) =>
+			for item in new.iter().map(strip) {
+				if ! orig.contains(&item) { orig.push(item); }
+			},
+		(Yaml::Array(orig), Yaml::Array(new), Strategy::Append) => orig.extend(new.iter().map(strip)),
+		(Yaml::Hash(orig), Yaml::Hash(new), _) if strategy != Some(Strategy::Replace) => {
+			let overrides = match new.get(&Yaml::String("_merge".to_string())) {
+				Some(Yaml::Hash(h)) => h.iter().map(|(k, v)| Ok((k.clone(), Strategy::from_yaml(v)?))).collect::<Result<Vec<(Yaml, Strategy)>>>()?,
+				Some(_) => bail!("_merge must be a mapping from keys to merge strategies"),
+				None => vec![],
+			};
+			let strategy_for = |k: &Yaml| overrides.iter().find(|o| o.0 == *k).map(|o| o.1);
+			for (k, v) in new.iter().filter(|(k, _)| k.as_str() != Some("_merge")) { // Existing keys keep their position, and new ones are added at the end
+				match (orig.get_mut(k), strategy_for(k)) {
+					(_, Some(Strategy::Delete)) => (),
+					(Some(existing), strategy) => recursive_merge(existing, v, strategy, default).chain_err(|| format!("Failed to merge {:?}", k))?,
+					(None, _) => { orig.insert(k.clone(), strip(v)); },
 				}
-			(orig, new) => *orig = new.clone(),
-		}
+			}
+			for (k, _) in overrides.iter().filter(|o| o.1 == Strategy::Delete) { orig.remove(k); }
+		},
+		(orig, new, _) => *orig = strip(new),
 	}
-	yamls.into_iter().fold(Yaml::Hash(BTreeMap::new()), |mut ret, cur| { // Flatten all YAMLs into one sequence, and then fold successive ones into earlier ones
-		recursive_merge(&mut ret, &cur); // Each fold appends lists, recursively merges objects, and overwrites everything else with the new values
-		ret
+	Ok(())
+}
+
+pub fn merge(yamls: Vec<Yaml>) -> Result<Yaml> {
+	let default = match yamls.iter().rev().map(|y| &y["_config"]["merge"]).find(|y| ! y.is_badvalue()) { // The last file to set the strategy wins
+		Some(Yaml::String(ref s)) if s == "delete" => bail!("_config.merge can't be delete"),
+		Some(y) => Strategy::from_yaml(y).chain_err(|| "Invalid _config.merge")?,
+		None => Strategy::Append,
+	};
+	yamls.into_iter().try_fold(Yaml::Hash(Hash::new()), |mut ret, cur| { // Flatten all YAMLs into one sequence, and then fold successive ones into earlier ones
+		recursive_merge(&mut ret, &cur, None, default)?;
+		Ok(ret)
 	})
 }
 
+pub fn sort_keys(yaml: &mut Yaml) { // Recursively reorders all mappings by key
+	match yaml {
+		Yaml::Hash(h) => {
+			let mut entries = ::std::mem::replace(h, Hash::new()).into_iter().collect::<Vec<(Yaml, Yaml)>>();
+			entries.sort_by(|a, b| a.0.cmp(&b.0));
+			for (k, mut v) in entries {
+				sort_keys(&mut v);
+				h.insert(k, v);
+			}
+		},
+		Yaml::Array(a) => for v in a.iter_mut() { sort_keys(v) },
+		_ => (),
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
-	use ::std::collections::BTreeMap;
-	use ::yaml_rust::YamlLoader;
 	#[test]
 	fn pathjoin_basic() {
 		use YamlPathElem::*;
-		assert_eq!(pathjoin(&vec![]), vec![]);
-		assert_eq!(pathjoin(&vec![
+		assert_eq!(pathjoin(&[]), vec![]);
+		assert_eq!(pathjoin(&[
 			&vec![Down("a".to_string()), Down(1.to_string())],
 			&vec![Down(0.to_string())],
 		]), vec![Down("a".to_string()), Down(1.to_string()), Down(0.to_string())]);
-		assert_eq!(pathjoin(&vec![
+		assert_eq!(pathjoin(&[
 			&vec![Down("a".to_string()), Down(1.to_string())],
 			&vec![Root, Down("b".to_string()), Down("c".to_string())],
 			&vec![Up, Down(10.to_string())],
 		]), vec![Down("b".to_string()), Down(10.to_string())]);
-		assert_eq!(pathjoin(&vec![
+		assert_eq!(pathjoin(&[
 			&vec![Root, Up, Up],
 			&vec![Down("x".to_string()), Up, Up, Up],
 			&vec![Down("y".to_string())],
@@ -131,7 +259,7 @@ nothing: null
 			Yaml::Null,
 			Yaml::BadValue,
 			Yaml::Array(vec![]),
-			Yaml::Hash(BTreeMap::new())
+			Yaml::Hash(Hash::new())
 		];
 		let good = vec![
 			Yaml::String("".to_string()),
@@ -145,6 +273,21 @@ nothing: null
 		for item in good.into_iter() { assert!(bool(&item)) }
 	}
 	#[test]
+	fn compare_basic() {
+		let real = |s: &str| Yaml::Real(s.to_string());
+		assert_eq!(compare(&Yaml::Integer(2), &Yaml::Integer(10)), Some(Ordering::Less));
+		assert_eq!(compare(&real("2.5"), &Yaml::Integer(2)), Some(Ordering::Greater));
+		assert_eq!(compare(&Yaml::String("b".to_string()), &Yaml::String("a".to_string())), Some(Ordering::Greater));
+		assert_eq!(compare(&Yaml::Boolean(false), &Yaml::Boolean(true)), Some(Ordering::Less));
+		assert_eq!(compare(&Yaml::Integer(1), &Yaml::String("1".to_string())), None);
+		assert_eq!(compare(&Yaml::Null, &Yaml::Null), None);
+		let mut items = vec![Yaml::BadValue, Yaml::Boolean(true), Yaml::String("a".to_string()), real("1.5"), Yaml::Integer(1)];
+		items.sort_by(|a, b| sort_order(a, b, false));
+		assert_eq!(items, vec![Yaml::Integer(1), real("1.5"), Yaml::String("a".to_string()), Yaml::Boolean(true), Yaml::BadValue]);
+		items.sort_by(|a, b| sort_order(a, b, true));
+		assert_eq!(items, vec![Yaml::Boolean(true), Yaml::String("a".to_string()), real("1.5"), Yaml::Integer(1), Yaml::BadValue]);
+	}
+	#[test]
 	fn string_basic() {
 		assert_eq!(string(&Yaml::String("".to_string()), false).unwrap(), "");
 		assert_eq!(string(&Yaml::String("hello".to_string()), false).unwrap(), "hello");
@@ -153,13 +296,74 @@ nothing: null
 		assert_eq!(string(&Yaml::Real("2.5".to_string()), false).unwrap(), "2.5");
 		assert_eq!(string(&Yaml::Boolean(true), false).unwrap(), "true");
 		assert_eq!(string(&Yaml::BadValue, true).unwrap(), "");
-		assert_eq!(string(&Yaml::Hash(BTreeMap::new()), true).unwrap(), "");
+		assert_eq!(string(&Yaml::Hash(Hash::new()), true).unwrap(), "");
 		assert_eq!(string(&Yaml::Array(vec![]), true).unwrap(), "");
 		assert!(string(&Yaml::BadValue, false).is_err());
-		assert!(string(&Yaml::Hash(BTreeMap::new()), false).is_err());
+		assert!(string(&Yaml::Hash(Hash::new()), false).is_err());
 		assert!(string(&Yaml::Array(vec![]), false).is_err());
 	}
 	#[test]
+	fn load_anchors() {
+		let doc = &load("base: &base {a: 1, b: [x]}\ncopy: *base\nlist: [*base, *base]", None).unwrap()[0];
+		assert_eq!(doc["copy"], doc["base"]);
+		assert_eq!(doc["list"][1]["b"][0].as_str(), Some("x"));
+	}
+	#[test]
+	fn load_merge_keys() {
+		let doc = &load("
+base: &base {a: 1, b: 2}
+other: &other {b: 3, c: 4}
+one:
+  <<: *base
+  b: 5
+many:
+  a: 0
+  <<: [*other, *base]
+nested:
+  <<: {<<: *base, d: 6}
+", None).unwrap()[0];
+		let keys = |yaml: &Yaml| yaml.as_hash().unwrap().iter().map(|(k, v)| (k.as_str().unwrap().to_string(), v.as_i64().unwrap())).collect::<Vec<(String, i64)>>();
+		let pairs = |p: &[(&str, i64)]| p.iter().map(|(k, v)| (k.to_string(), *v)).collect::<Vec<(String, i64)>>();
+		assert_eq!(keys(&doc["one"]), pairs(&[("a", 1), ("b", 5)]));
+		assert_eq!(keys(&doc["many"]), pairs(&[("a", 0), ("b", 3), ("c", 4)]));
+		assert_eq!(keys(&doc["nested"]), pairs(&[("a", 1), ("b", 2), ("d", 6)]));
+		assert!(load("a: &a [1]\nb: {<<: *a}", None).is_err());
+		assert!(load("b: {<<: [1]}", None).is_err());
+	}
+	#[test]
+	fn load_documents() {
+		let text = "a: 1\n---\na: 2\n---\nb: 3";
+		assert_eq!(load(text, None).unwrap().len(), 3);
+		assert_eq!(load(text, Some(1)).unwrap(), vec![YamlLoader::load_from_str("a: 2").unwrap().remove(0)]);
+		assert!(load(text, Some(3)).is_err());
+		assert_eq!(merge(load(text, None).unwrap()).unwrap(), YamlLoader::load_from_str("a: 2\nb: 3").unwrap().remove(0));
+	}
+	#[test]
+	fn merge_strategies() {
+		let load = |s: &str| YamlLoader::load_from_str(s).unwrap().into_iter().next().unwrap();
+		let base = "a: [1, 2]\nb: {c: 1, d: [1]}\ne: 3";
+		let check = |over: &str, expected: &str| assert_eq!(merge(vec![load(base), load(over)]).unwrap(), load(expected));
+		check("_config: {merge: replace}\na: [2, 3]\nb: {d: [2]}", "a: [2, 3]\nb: {c: 1, d: [2]}\ne: 3\n_config: {merge: replace}");
+		check("_config: {merge: prepend}\na: [2, 3]", "a: [2, 3, 1, 2]\nb: {c: 1, d: [1]}\ne: 3\n_config: {merge: prepend}");
+		check("_config: {merge: unique}\na: [2, 3, 3]", "a: [1, 2, 3]\nb: {c: 1, d: [1]}\ne: 3\n_config: {merge: unique}");
+		check("_merge: {a: replace, e: delete}\na: [3]\ne: 4", "a: [3]\nb: {c: 1, d: [1]}");
+		check("b:\n  _merge: {c: delete, d: prepend}\n  d: [0]", "a: [1, 2]\nb: {d: [0, 1]}\ne: 3");
+		check("_merge: {b: replace}\nb: {f: {_merge: {}, g: 1}}", "a: [1, 2]\nb: {f: {g: 1}}\ne: 3");
+		assert!(merge(vec![load(base), load("_merge: {a: sideways}")]).is_err());
+		assert!(merge(vec![load(base), load("_merge: [a]")]).is_err());
+		assert!(merge(vec![load("_config: {merge: delete}")]).is_err());
+	}
+	#[test]
+	fn sort_keys_basic() {
+		let keys = |yaml: &Yaml| yaml.as_hash().unwrap().keys().map(|k| k.as_str().unwrap().to_string()).collect::<Vec<String>>();
+		let mut doc = YamlLoader::load_from_str("b: 1\nc: [{z: 1, y: 2}]\na: {x: 1, w: 2}").unwrap().into_iter().next().unwrap();
+		assert_eq!(keys(&doc), vec!["b", "c", "a"]);
+		sort_keys(&mut doc);
+		assert_eq!(keys(&doc), vec!["a", "b", "c"]);
+		assert_eq!(keys(&doc["a"]), vec!["w", "x"]);
+		assert_eq!(keys(&doc["c"][0]), vec!["y", "z"]);
+	}
+	#[test]
 	fn merge_basic() {
 		let doc1 = YamlLoader::load_from_str("
 arr_append:
@@ -221,6 +425,9 @@ new_object:
   w: 1
   x: null
 ").unwrap().into_iter().next().unwrap();
-		assert_eq!(merge(vec![doc1, doc2]), doc_res);
+		let merged = merge(vec![doc1, doc2]).unwrap();
+		let order = merged.as_hash().unwrap().keys().map(|k| k.as_str().unwrap()).collect::<Vec<&str>>();
+		assert_eq!(order, vec!["arr_append", "arr_untouched", "obj_append", "obj_modify", "obj_untouched", "number", "change_type", "new_object"]);
+		assert_eq!(merged, doc_res);
 	}
 }